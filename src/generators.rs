@@ -0,0 +1,413 @@
+//! The sfxr sound effect generators, and variations on existing instruments. All of them take
+//! the random number generator so that a seeded one gives the same sound every time.
+
+use rand::Rng;
+
+use super::{
+    internal::Params,
+    params::{Param, Unit, PARAMS},
+    Duty, Instrument, Waveform,
+};
+
+/// A random value from zero up to `range`.
+fn frnd<R: Rng + ?Sized>(rng: &mut R, range: f32) -> f32 {
+    rng.gen::<f32>() * range
+}
+
+/// A random value between -1 and 1.
+fn signed<R: Rng + ?Sized>(rng: &mut R) -> f32 {
+    frnd(rng, 2.0) - 1.0
+}
+
+/// One of the four original sfxr waveforms, numbered as in sfxr.
+fn waveform(wave_type: u32) -> Waveform {
+    match wave_type {
+        0 => Waveform::Square,
+        1 => Waveform::Sawtooth,
+        2 => Waveform::Sine,
+        _ => Waveform::Noise,
+    }
+}
+
+/// Where `value` lies in the range of `param`, from 0 to 1. Frequencies are measured in
+/// octaves, so that every octave of a cutoff gets the same share.
+fn position(param: &Param, value: f32) -> f32 {
+    match param.unit {
+        Unit::Hertz => f32::ln(value / param.min) / f32::ln(param.max / param.min),
+        _ => (value - param.min) / (param.max - param.min),
+    }
+}
+
+/// The value at `position` in the range of `param`, see [`position`].
+fn value_at(param: &Param, position: f32) -> f32 {
+    match param.unit {
+        Unit::Hertz => param.min * f32::powf(param.max / param.min, position),
+        _ => param.min + (param.max - param.min) * position,
+    }
+}
+
+/// Wraps up generated parameters, pulling values that went out of range back in.
+fn instrument(mut params: Params) -> Instrument {
+    for param in PARAMS {
+        let value = param.get(&params);
+        param.set(&mut params, value);
+    }
+
+    Instrument { params }
+}
+
+impl Instrument {
+    /// A coin or pickup: a short bright blip, often with a jump up.
+    pub fn random_pickup<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut params = Params {
+            freq: 0.4 + frnd(rng, 0.5),
+            sustain: frnd(rng, 0.1),
+            decay: 0.1 + frnd(rng, 0.4),
+            punch: 0.3 + frnd(rng, 0.3),
+            ..Default::default()
+        };
+        if rng.gen_bool(0.5) {
+            params.aspeed = 0.5 + frnd(rng, 0.2);
+            params.arp = 0.2 + frnd(rng, 0.4);
+        }
+
+        instrument(params)
+    }
+
+    /// A laser or shot: a falling sweep.
+    pub fn random_laser<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut wave_type = rng.gen_range(0..=2);
+        if wave_type == 2 && rng.gen_bool(0.5) {
+            wave_type = rng.gen_range(0..=1);
+        }
+        let mut params = Params {
+            r#type: waveform(wave_type),
+            freq: 0.5 + frnd(rng, 0.5),
+            ..Default::default()
+        };
+        params.limit = f32::max(params.freq - 0.2 - frnd(rng, 0.6), 0.2);
+        params.slide = -0.15 - frnd(rng, 0.2);
+        if rng.gen_ratio(1, 3) {
+            params.freq = 0.3 + frnd(rng, 0.6);
+            params.limit = frnd(rng, 0.1);
+            params.slide = -0.35 - frnd(rng, 0.3);
+        }
+        if rng.gen_bool(0.5) {
+            params.square = frnd(rng, 0.5);
+            params.sweep = frnd(rng, 0.2);
+        } else {
+            params.square = 0.4 + frnd(rng, 0.5);
+            params.sweep = -frnd(rng, 0.7);
+        }
+        params.sustain = 0.1 + frnd(rng, 0.2);
+        params.decay = frnd(rng, 0.4);
+        if rng.gen_bool(0.5) {
+            params.punch = frnd(rng, 0.3);
+        }
+        if rng.gen_ratio(1, 3) {
+            params.phase = frnd(rng, 0.2);
+            params.psweep = -frnd(rng, 0.2);
+        }
+        if rng.gen_bool(0.5) {
+            params.hpf = frnd(rng, 0.3);
+        }
+
+        instrument(params)
+    }
+
+    /// An explosion: noise with a strong punch.
+    pub fn random_explosion<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut params = Params {
+            r#type: Waveform::Noise,
+            ..Default::default()
+        };
+        if rng.gen_bool(0.5) {
+            params.freq = 0.1 + frnd(rng, 0.4);
+            params.slide = -0.1 + frnd(rng, 0.4);
+        } else {
+            params.freq = 0.2 + frnd(rng, 0.7);
+            params.slide = -0.2 - frnd(rng, 0.2);
+        }
+        params.freq *= params.freq;
+        if rng.gen_ratio(1, 5) {
+            params.slide = 0.0;
+        }
+        if rng.gen_ratio(1, 3) {
+            params.repeat = 0.3 + frnd(rng, 0.5);
+        }
+        params.sustain = 0.1 + frnd(rng, 0.3);
+        params.decay = frnd(rng, 0.5);
+        if rng.gen_bool(0.5) {
+            params.phase = -0.3 + frnd(rng, 0.9);
+            params.psweep = -frnd(rng, 0.3);
+        }
+        params.punch = 0.2 + frnd(rng, 0.6);
+        if rng.gen_bool(0.5) {
+            params.vibe = frnd(rng, 0.7);
+            params.vspeed = frnd(rng, 0.6);
+        }
+        if rng.gen_ratio(1, 3) {
+            params.aspeed = 0.6 + frnd(rng, 0.3);
+            params.arp = 0.8 - frnd(rng, 1.6);
+        }
+
+        instrument(params)
+    }
+
+    /// A powerup: a rising sweep, sometimes repeating.
+    pub fn random_powerup<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut params = Params::default();
+        if rng.gen_bool(0.5) {
+            params.r#type = Waveform::Sawtooth;
+        } else {
+            params.square = frnd(rng, 0.6);
+        }
+        params.freq = 0.2 + frnd(rng, 0.3);
+        if rng.gen_bool(0.5) {
+            params.slide = 0.1 + frnd(rng, 0.4);
+            params.repeat = 0.4 + frnd(rng, 0.4);
+        } else {
+            params.slide = 0.05 + frnd(rng, 0.2);
+            if rng.gen_bool(0.5) {
+                params.vibe = frnd(rng, 0.7);
+                params.vspeed = frnd(rng, 0.6);
+            }
+        }
+        params.sustain = frnd(rng, 0.4);
+        params.decay = 0.1 + frnd(rng, 0.4);
+
+        instrument(params)
+    }
+
+    /// A hit or hurt sound: short and falling.
+    pub fn random_hit<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let wave_type = match rng.gen_range(0..=2) {
+            2 => 3,
+            wave_type => wave_type,
+        };
+        let mut params = Params {
+            r#type: waveform(wave_type),
+            ..Default::default()
+        };
+        if wave_type == 0 {
+            params.square = frnd(rng, 0.6);
+        }
+        params.freq = 0.2 + frnd(rng, 0.6);
+        params.slide = -0.3 - frnd(rng, 0.4);
+        params.sustain = frnd(rng, 0.1);
+        params.decay = 0.1 + frnd(rng, 0.2);
+        if rng.gen_bool(0.5) {
+            params.hpf = frnd(rng, 0.3);
+        }
+
+        instrument(params)
+    }
+
+    /// A jump: a square wave sliding up.
+    pub fn random_jump<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut params = Params {
+            square: frnd(rng, 0.6),
+            freq: 0.3 + frnd(rng, 0.3),
+            slide: 0.1 + frnd(rng, 0.2),
+            sustain: 0.1 + frnd(rng, 0.3),
+            decay: 0.1 + frnd(rng, 0.2),
+            ..Default::default()
+        };
+        if rng.gen_bool(0.5) {
+            params.hpf = frnd(rng, 0.3);
+        }
+        if rng.gen_bool(0.5) {
+            params.lpf = 1.0 - frnd(rng, 0.6);
+        }
+
+        instrument(params)
+    }
+
+    /// A blip for menus and dialogue.
+    pub fn random_blip<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let wave_type = rng.gen_range(0..=1);
+        let mut params = Params {
+            r#type: waveform(wave_type),
+            ..Default::default()
+        };
+        if wave_type == 0 {
+            params.square = frnd(rng, 0.6);
+        }
+        params.freq = 0.2 + frnd(rng, 0.4);
+        params.sustain = 0.1 + frnd(rng, 0.1);
+        params.decay = frnd(rng, 0.2);
+        params.hpf = 0.1;
+
+        instrument(params)
+    }
+
+    /// Anything at all, with every sfxr parameter randomized.
+    pub fn random<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut params = Params {
+            r#type: waveform(rng.gen_range(0..=3)),
+            ..Default::default()
+        };
+        params.freq = if rng.gen_bool(0.5) {
+            signed(rng).powi(3) + 0.5
+        } else {
+            signed(rng).powi(2)
+        };
+        params.slide = signed(rng).powi(5);
+        if (params.freq > 0.7 && params.slide > 0.2) || (params.freq < 0.2 && params.slide < -0.05)
+        {
+            params.slide = -params.slide;
+        }
+        params.dslide = signed(rng).powi(3);
+        params.square = frnd(rng, 1.0);
+        params.sweep = signed(rng).powi(3);
+        params.vibe = frnd(rng, 1.0).powi(3);
+        params.vspeed = frnd(rng, 1.0);
+        params.vdelay = frnd(rng, 1.0);
+        params.attack = frnd(rng, 1.0).powi(3);
+        params.sustain = frnd(rng, 1.0).powi(2);
+        params.decay = frnd(rng, 1.0);
+        params.punch = frnd(rng, 0.8).powi(2);
+        // make sure there is something to hear
+        if params.attack + params.sustain + params.decay < 0.2 {
+            params.sustain += 0.2 + frnd(rng, 0.3);
+            params.decay += 0.2 + frnd(rng, 0.3);
+        }
+        params.resonance = frnd(rng, 1.0);
+        params.lpf = 1.0 - frnd(rng, 1.0).powi(3);
+        params.lsweep = signed(rng).powi(3);
+        if params.lpf < 0.1 && params.lsweep < -0.05 {
+            params.lsweep = -params.lsweep;
+        }
+        params.hpf = frnd(rng, 1.0).powi(5);
+        params.hsweep = signed(rng).powi(5);
+        params.phase = signed(rng).powi(3);
+        params.psweep = signed(rng).powi(3);
+        params.repeat = frnd(rng, 1.0);
+        params.aspeed = frnd(rng, 1.0);
+        params.arp = signed(rng);
+
+        instrument(params)
+    }
+
+    /// Nudges every parameter by up to `amount` of its range (in octaves for frequencies),
+    /// while toggles flip with a chance of `amount`. The waveform, filter mode and slope have
+    /// nothing in between to move to and stay as they are, as do the pan, the LFOs and the
+    /// modulation routes.
+    pub fn mutate<R: Rng + ?Sized>(&mut self, amount: f32, rng: &mut R) {
+        let amount = amount.clamp(0.0, 1.0);
+        for param in PARAMS {
+            let value = param.get(&self.params);
+            if param.unit == Unit::Toggle {
+                if rng.gen_bool(amount as f64) {
+                    param.set(&mut self.params, 1.0 - value);
+                }
+                continue;
+            }
+            let offset = signed(rng) * amount;
+            param.set(
+                &mut self.params,
+                value_at(param, position(param, value) + offset),
+            );
+        }
+    }
+
+    /// Blends two instruments, `t` going from 0 for `a` to 1 for `b`. Settings that can't be
+    /// blended switch over halfway, apart from the duty cycles of two pulse waves which step
+    /// through the ones in between. The pan, the LFOs and the modulation routes switch over
+    /// halfway too.
+    pub fn lerp(a: &Instrument, b: &Instrument, t: f32) -> Instrument {
+        let t = t.clamp(0.0, 1.0);
+        let mut params = if t < 0.5 { &a.params } else { &b.params }.clone();
+        for param in PARAMS.iter().filter(|param| param.unit != Unit::Toggle) {
+            let from = position(param, param.get(&a.params));
+            let to = position(param, param.get(&b.params));
+            param.set(&mut params, value_at(param, from + (to - from) * t));
+        }
+        if let (Waveform::Pulse(from), Waveform::Pulse(to)) = (&a.params.r#type, &b.params.r#type) {
+            let ratio = from.ratio() + (to.ratio() - from.ratio()) * t;
+            params.r#type = Waveform::Pulse(Duty::nearest(ratio));
+        }
+
+        Instrument { params }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn seeded_generators_repeat() {
+        let generators: [fn(&mut StdRng) -> Instrument; 8] = [
+            Instrument::random_pickup,
+            Instrument::random_laser,
+            Instrument::random_explosion,
+            Instrument::random_powerup,
+            Instrument::random_hit,
+            Instrument::random_jump,
+            Instrument::random_blip,
+            Instrument::random,
+        ];
+
+        for generate in generators {
+            for seed in 0..20 {
+                let a = generate(&mut StdRng::seed_from_u64(seed));
+                let b = generate(&mut StdRng::seed_from_u64(seed));
+                assert_eq!(a.params.r#type, b.params.r#type);
+                for param in PARAMS {
+                    let value = param.get(&a.params);
+                    assert_eq!(value, param.get(&b.params), "{}", param.name);
+                    assert!((param.min..=param.max).contains(&value), "{}", param.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutate_and_lerp_stay_in_range() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let a = Instrument::random_laser(&mut rng);
+        let mut b = Instrument::random_explosion(&mut rng);
+        b.mutate(0.5, &mut rng);
+
+        let halfway = Instrument::lerp(&a, &b, 0.5);
+        assert_eq!(halfway.params.r#type, Waveform::Noise);
+        for param in PARAMS {
+            let (from, to) = (param.get(&a.params), param.get(&b.params));
+            let value = param.get(&halfway.params);
+            assert!((param.min..=param.max).contains(&param.get(&b.params)));
+            let expected = match param.unit {
+                Unit::Hertz => f32::sqrt(from * to),
+                // toggles switch over halfway, along with the waveform
+                Unit::Toggle => to,
+                _ => (from + to) / 2.0,
+            };
+            assert!((value - expected).abs() < 1e-3, "{}", param.name);
+        }
+        assert_eq!(Instrument::lerp(&a, &b, 0.0).params.r#type, a.params.r#type);
+    }
+
+    #[test]
+    fn mutate_moves_every_param() {
+        let original = Instrument::builder().build();
+        let mut mutated = original.clone();
+        mutated.mutate(0.1, &mut StdRng::seed_from_u64(3));
+        for param in PARAMS {
+            let value = param.get(&original.params);
+            // parameters at the edge of their range may be pushed against it
+            if param.unit != Unit::Toggle && value > param.min && value < param.max {
+                assert_ne!(param.get(&mutated.params), value, "{}", param.name);
+            }
+        }
+    }
+
+    #[test]
+    fn frequencies_blend_by_octaves() {
+        let a = Instrument::builder().with_cutoff(100.0).build();
+        let b = Instrument::builder().with_cutoff(10000.0).build();
+        let cutoff = Instrument::lerp(&a, &b, 0.5).params.cutoff;
+        assert!((cutoff - 1000.0).abs() < 0.1, "{cutoff}");
+    }
+}
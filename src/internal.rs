@@ -1,7 +1,21 @@
 mod notation;
 
+const LO_OCTAVE: u8 = 1;
 const HI_OCTAVE: u8 = 8;
-const MAX_TRACKS: usize = 4;
+pub(super) const MAX_TRACKS: usize = 4;
+const MAX_POLYPHONY: usize = 8;
+/// Resolution of note durations: divisible by every power of two down to 64th notes with
+/// up to two dots, as well as by 3, 5 and 7 for tuplets.
+const TICKS_PER_WHOLE: u32 = 80640;
+const TICKS_PER_BEAT: u32 = TICKS_PER_WHOLE / 4;
+pub(super) const DEFAULT_POLYPHONY: usize = 4;
+const MAX_LIVE_VOICES: usize = 16;
+/// Tempo that tempo-synced LFOs follow on live notes.
+const LIVE_TEMPO: f32 = 120.0;
+/// Default length of the anti-click ramps in seconds.
+pub(super) const DECLICK_TIME: f32 = 0.005;
+/// Velocity of notes without an accent, which plays at the instrument's own volume.
+const DEFAULT_VELOCITY: u8 = 100;
 
 const UNUSED_VOICE: Option<Voice> = None;
 
@@ -14,26 +28,14 @@ pub(super) enum State {
 
 #[derive(Debug, Clone)]
 pub(super) enum FxCommand {
-    Volume,
-    Punch,
-    Attack,
-    Sustain,
-    Decay,
-    Sweep,
-    Square,
-    Vibe,
-    VSpeed,
-    VDelay,
-    Lpf,
-    LSweep,
-    Resonance,
-    Hpf,
-    HSweep,
-    Arp,
-    ASpeed,
-    Phase,
-    PSweep,
-    Repeat,
+    /// Sets one of the numeric parameters.
+    Param(&'static super::params::Param),
+    /// Picks the nearest duty cycle of a `Pulse` waveform, ignored for any other waveform.
+    Duty,
+    Tempo,
+    Time(u8, u8),
+    /// Swaps in all parameters of a named instrument.
+    Instrument(String),
 }
 
 #[derive(Clone)]
@@ -79,6 +81,36 @@ pub(super) struct Params {
 
     // repeats?
     pub repeat: f32,
+
+    // frequency modulation
+    pub fmratio: f32,
+    pub fmindex: f32,
+    pub fmattack: f32,
+    pub fmdecay: f32,
+
+    // ring modulation
+    pub ring: f32,
+    pub rratio: f32,
+
+    // multimode filter
+    pub filter: super::FilterMode,
+    pub slope: super::FilterSlope,
+    pub cutoff: f32,
+    pub q: f32,
+    pub fenv: f32,
+
+    // note transitions
+    pub glide: f32,
+    pub legato: bool,
+
+    // ADSR envelope, replacing the sfxr one when enabled
+    pub adsr: bool,
+    pub slevel: f32,
+    pub release: f32,
+
+    // modulation matrix
+    pub lfos: Vec<(super::LfoShape, super::LfoRate)>,
+    pub modulation: Vec<(super::ModSource, super::ModTarget, f32)>,
 }
 
 impl Default for Params {
@@ -110,205 +142,374 @@ impl Default for Params {
             phase: Default::default(),
             psweep: Default::default(),
             repeat: Default::default(),
+            fmratio: 0.0625,
+            fmindex: Default::default(),
+            fmattack: Default::default(),
+            fmdecay: Default::default(),
+            ring: Default::default(),
+            rratio: 0.0625,
+            filter: Default::default(),
+            slope: Default::default(),
+            cutoff: 1000.0,
+            q: std::f32::consts::FRAC_1_SQRT_2,
+            fenv: Default::default(),
+            glide: Default::default(),
+            legato: Default::default(),
+            adsr: Default::default(),
+            slevel: 0.5,
+            release: 0.2,
+            lfos: Vec::new(),
+            modulation: Vec::new(),
         }
     }
 }
 
-macro_rules! fx {
-    ($f:ident, $a:ident, $v:ident) => {{
-        if $f.r#mod == '+' {
-            $a.params.$v += $f.val;
-        } else if $f.r#mod == '-' {
-            $a.params.$v -= $f.val;
-        } else {
-            $a.params.$v = $f.val;
-        }
-
-        if $a.params.$v > 1.0 {
-            $a.params.$v = 1.0;
-        } else if $a.params.$v < 0.0 {
-            $a.params.$v = 0.0;
-        }
-    }};
-}
-
 #[derive(Debug, Clone)]
 pub(super) struct Fx {
     command: FxCommand,
     val: f32,
     r#mod: char,
+    /// Number of beats to ramp over instead of changing at once.
+    over: Option<f32>,
+    /// Value a ramp starts from, rather than the current one.
+    from: Option<f32>,
+    /// Ramps by equal ratios instead of equal steps, which suits frequencies.
+    exponential: bool,
 }
 
 #[derive(Debug, Clone)]
 pub(super) struct Note {
     tone: char,
     octave: u8,
-    duration: u8,
+    /// Length in ticks, see [`TICKS_PER_WHOLE`].
+    duration: u32,
     fx: Vec<Fx>,
+    /// Further pitches sounding together with this one.
+    chord: Vec<(char, u8)>,
+    /// Loudness from 0 to 127, scaling the voice without touching its parameters.
+    velocity: u8,
+    /// Slurred from the previous note, which keeps the envelope running.
+    legato: bool,
 }
 
 impl Note {
-    fn freq(&self) -> f32 {
-        match self.tone {
-            'A' =>
-            // A
-            {
-                match self.octave {
-                    1 => 0.121,
-                    2 => 0.175,
-                    3 => 0.248,
-                    4 => 0.353,
-                    5 => 0.500,
-                    _ => 0.0,
-                }
+    fn gain(&self) -> f32 {
+        self.velocity as f32 / DEFAULT_VELOCITY as f32
+    }
+
+    fn freq(&self, semitones: i32) -> f32 {
+        let (tone, octave) = transpose(self.tone, self.octave, semitones);
+        tone_freq(tone, octave)
+    }
+}
+
+/// Coefficient and damping of the legacy low-pass filter.
+pub(super) fn lowpass(lpf: f32, resonance: f32) -> (f32, f32) {
+    let filter2 = f32::powf(lpf, 3.0) * 0.1;
+    let filter4 = 5.0 / (1.0 + f32::powf(resonance, 2.0) * 20.0) * (0.01 + filter2);
+    (filter2, f32::min(filter4, 0.8))
+}
+
+/// Tone letters in semitone order, with the lowercase letters standing for the sharps.
+pub(super) const CHROMATIC: [char; 12] =
+    ['C', 'd', 'D', 'e', 'E', 'F', 'g', 'G', 'a', 'A', 'b', 'B'];
+
+/// Shifts a tone by a number of semitones, moving to a neighbouring octave when needed and
+/// stopping at the lowest or highest playable note. Rests and other non-tones are left alone.
+pub(super) fn transpose(tone: char, octave: u8, semitones: i32) -> (char, u8) {
+    let Some(index) = CHROMATIC.iter().position(|&t| t == tone) else {
+        return (tone, octave);
+    };
+    let index = (octave as i32 * 12 + index as i32 + semitones)
+        .clamp(LO_OCTAVE as i32 * 12, HI_OCTAVE as i32 * 12 + 11);
+
+    (CHROMATIC[index as usize % 12], (index / 12) as u8)
+}
+
+fn tone_freq(tone: char, octave: u8) -> f32 {
+    let freq = match tone {
+        'A' =>
+        // A
+        {
+            match octave {
+                1 => 0.121,
+                2 => 0.175,
+                3 => 0.248,
+                4 => 0.353,
+                5 => 0.500,
+                _ => 0.0,
             }
+        }
 
-            'b' =>
-            // A# or Bb
-            {
-                match self.octave {
-                    1 => 0.125,
-                    2 => 0.181,
-                    3 => 0.255,
-                    4 => 0.364,
-                    5 => 0.516,
-                    _ => 0.0,
-                }
+        'b' =>
+        // A# or Bb
+        {
+            match octave {
+                1 => 0.125,
+                2 => 0.181,
+                3 => 0.255,
+                4 => 0.364,
+                5 => 0.516,
+                _ => 0.0,
             }
-            'B' =>
-            // B
-            {
-                match self.octave {
-                    1 => 0.129,
-                    2 => 0.187,
-                    3 => 0.263,
-                    4 => 0.374,
-                    5 => 0.528,
-                    _ => 0.0,
-                }
+        }
+        'B' =>
+        // B
+        {
+            match octave {
+                1 => 0.129,
+                2 => 0.187,
+                3 => 0.263,
+                4 => 0.374,
+                5 => 0.528,
+                _ => 0.0,
             }
+        }
 
-            'C' =>
-            // C
-            {
-                match self.octave {
-                    2 => 0.133,
-                    3 => 0.192,
-                    4 => 0.271,
-                    5 => 0.385,
-                    6 => 0.544,
-                    _ => 0.0,
-                }
+        'C' =>
+        // C
+        {
+            match octave {
+                2 => 0.133,
+                3 => 0.192,
+                4 => 0.271,
+                5 => 0.385,
+                6 => 0.544,
+                _ => 0.0,
             }
-            'd' =>
-            // C# or Db
-            {
-                match self.octave {
-                    2 => 0.138,
-                    3 => 0.198,
-                    4 => 0.279,
-                    5 => 0.395,
-                    6 => 0.559,
-                    _ => 0.0,
-                }
+        }
+        'd' =>
+        // C# or Db
+        {
+            match octave {
+                2 => 0.138,
+                3 => 0.198,
+                4 => 0.279,
+                5 => 0.395,
+                6 => 0.559,
+                _ => 0.0,
             }
-            'D' =>
-            // D
-            {
-                match self.octave {
-                    2 => 0.143,
-                    3 => 0.202,
-                    4 => 0.287,
-                    5 => 0.406,
-                    6 => 0.575,
-                    _ => 0.0,
-                }
+        }
+        'D' =>
+        // D
+        {
+            match octave {
+                2 => 0.143,
+                3 => 0.202,
+                4 => 0.287,
+                5 => 0.406,
+                6 => 0.575,
+                _ => 0.0,
             }
-            'e' =>
-            // D# or Eb
-            {
-                match self.octave {
-                    2 => 0.148,
-                    3 => 0.208,
-                    4 => 0.296,
-                    5 => 0.418,
-                    6 => 0.593,
-                    _ => 0.0,
-                }
+        }
+        'e' =>
+        // D# or Eb
+        {
+            match octave {
+                2 => 0.148,
+                3 => 0.208,
+                4 => 0.296,
+                5 => 0.418,
+                6 => 0.593,
+                _ => 0.0,
             }
-            'E' =>
-            // E
-            {
-                match self.octave {
-                    2 => 0.152,
-                    3 => 0.214,
-                    4 => 0.305,
-                    5 => 0.429,
-                    6 => 0.608,
-                    _ => 0.0,
-                }
+        }
+        'E' =>
+        // E
+        {
+            match octave {
+                2 => 0.152,
+                3 => 0.214,
+                4 => 0.305,
+                5 => 0.429,
+                6 => 0.608,
+                _ => 0.0,
             }
-            'F' =>
-            // F
-            {
-                match self.octave {
-                    2 => 0.155,
-                    3 => 0.220,
-                    4 => 0.314,
-                    5 => 0.441,
-                    _ => 0.0,
-                }
+        }
+        'F' =>
+        // F
+        {
+            match octave {
+                2 => 0.155,
+                3 => 0.220,
+                4 => 0.314,
+                5 => 0.441,
+                _ => 0.0,
             }
-            'g' =>
-            // F# or Gb
-            {
-                match self.octave {
-                    2 => 0.160,
-                    3 => 0.227,
-                    4 => 0.323,
-                    5 => 0.454,
-                    _ => 0.0,
-                }
+        }
+        'g' =>
+        // F# or Gb
+        {
+            match octave {
+                2 => 0.160,
+                3 => 0.227,
+                4 => 0.323,
+                5 => 0.454,
+                _ => 0.0,
             }
-            'G' =>
-            // G
-            {
-                match self.octave {
-                    2 => 0.164,
-                    3 => 0.234,
-                    4 => 0.332,
-                    5 => 0.468,
-                    _ => 0.0,
-                }
+        }
+        'G' =>
+        // G
+        {
+            match octave {
+                2 => 0.164,
+                3 => 0.234,
+                4 => 0.332,
+                5 => 0.468,
+                _ => 0.0,
             }
-            'a' =>
-            // G# or Ab
-            {
-                match self.octave {
-                    1 => 0.117,
-                    2 => 0.170,
-                    3 => 0.242,
-                    4 => 0.343,
-                    5 => 0.485,
-                    _ => 0.0,
-                }
+        }
+        'a' =>
+        // G# or Ab
+        {
+            match octave {
+                1 => 0.117,
+                2 => 0.170,
+                3 => 0.242,
+                4 => 0.343,
+                5 => 0.485,
+                _ => 0.0,
             }
-            _ => 0.0,
         }
+        _ => 0.0,
+    };
+
+    match CHROMATIC.iter().position(|&t| t == tone) {
+        // equal temperament for the octaves the table doesn't cover
+        Some(index) if freq == 0.0 && (LO_OCTAVE..=HI_OCTAVE).contains(&octave) => {
+            midi_freq(octave * 12 + index as u8 + 12)
+        }
+        _ => freq,
     }
 }
 
+/// Frequency parameter of a MIDI note number, in equal temperament around A4 (69).
+fn midi_freq(pitch: u8) -> f32 {
+    let hz = 440.0 * f32::powf(2.0, (pitch as f32 - 69.0) / 12.0);
+    f32::sqrt(hz * 100.0 / (8.0 * 44100.0) - 0.001)
+}
+
 #[derive(Clone)]
 pub(super) struct Track {
     notes: Vec<Note>,
+    loop_start: Option<usize>,
     params: Params,
+    polyphony: usize,
+    warnings: Vec<super::Warning>,
 }
 
 impl Track {
     pub fn new(instrument: super::Instrument, tune: &str) -> Self {
+        let tune = notation::tune(tune).unwrap().1;
         Track {
-            notes: notation::tune(tune).unwrap().1,
+            notes: tune.notes,
+            loop_start: tune.loop_start,
             params: instrument.params,
+            polyphony: DEFAULT_POLYPHONY,
+            warnings: tune.warnings,
+        }
+    }
+
+    pub fn warnings(&self) -> &[super::Warning] {
+        &self.warnings
+    }
+
+    /// Names of the instruments the track switches to with `[inst name]`.
+    pub fn instruments(&self) -> impl Iterator<Item = &str> {
+        self.notes
+            .iter()
+            .flat_map(|note| &note.fx)
+            .filter_map(|fx| match &fx.command {
+                FxCommand::Instrument(name) => Some(name.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Limits how many notes of a chord sound at once on this track.
+    pub fn with_polyphony(mut self, polyphony: usize) -> Self {
+        self.polyphony = polyphony.clamp(1, MAX_POLYPHONY);
+        self
+    }
+}
+
+/// A parameter moving from one value to another across part of a track.
+#[derive(Clone, Copy)]
+struct Automation {
+    param: &'static super::params::Param,
+    from: f32,
+    to: f32,
+    /// Ticks at which the ramp starts and ends.
+    start: f64,
+    end: f64,
+    exponential: bool,
+}
+
+impl Automation {
+    /// Value at `ticks`, and whether the ramp has finished.
+    fn value(&self, ticks: f64) -> (f32, bool) {
+        let t = ((ticks - self.start) / (self.end - self.start)).clamp(0.0, 1.0) as f32;
+        // an exponential ramp can't cross or touch zero, those stay linear
+        let value = if self.exponential && self.from * self.to > 0.0 {
+            self.from * f32::powf(self.to / self.from, t)
+        } else {
+            self.from + (self.to - self.from) * t
+        };
+        (value, t >= 1.0)
+    }
+}
+
+/// Sum of the modulation routed to each target, see [`super::ModTarget`].
+#[derive(Default)]
+struct Modulation {
+    pitch: f32,
+    volume: f32,
+    cutoff: f32,
+    duty: f32,
+    phaser: f32,
+}
+
+#[derive(Clone, Copy)]
+struct SvfCoefficients {
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+}
+
+impl SvfCoefficients {
+    /// Trapezoidal state-variable filter coefficients, which stay stable for any cutoff below
+    /// Nyquist regardless of the sample rate.
+    fn new(cutoff: f32, q: f32, sample_rate: f32) -> Self {
+        let cutoff = cutoff.clamp(10.0, sample_rate * 0.45);
+        let g = f32::tan(core::f32::consts::PI * cutoff / sample_rate);
+        let k = 1.0 / q.max(0.1);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        SvfCoefficients { k, a1, a2, a3 }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Svf {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Svf {
+    fn process(&mut self, x: f32, c: SvfCoefficients, mode: super::FilterMode) -> f32 {
+        let v3 = x - self.ic2eq;
+        let v1 = c.a1 * self.ic1eq + c.a2 * v3;
+        let v2 = self.ic2eq + c.a2 * self.ic1eq + c.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        match mode {
+            super::FilterMode::Legacy | super::FilterMode::LowPass => v2,
+            super::FilterMode::HighPass => x - c.k * v1 - v2,
+            super::FilterMode::BandPass => v1,
+            super::FilterMode::Notch => x - c.k * v1,
         }
     }
 }
@@ -326,14 +527,14 @@ impl Default for Phaser {
 pub(super) struct Voice {
     track: Option<Track>,
     params: Params,
-    frames: i32,
-    nextnote: [i32; 2],
+    /// Tick at which the next note starts and the index of that note.
+    nextnote: [u64; 2],
     volume: f32,
     // freq: f32, // unused
     state: State,
     stage: i32,
     time: i32,
-    length: [i32; 3],
+    length: [i32; 4],
     period: f64,
     maxperiod: f64,
     slide: f64,
@@ -347,7 +548,11 @@ pub(super) struct Voice {
     dphase: f32,
     phaser: Phaser,
     noise: [f32; 32],
+    lfsr: u16,
+    lfsrstep: i32,
+    spos: f64,
     filter: [f32; 8],
+    svf: [Svf; 2],
     vibe: f32,
     vspeed: f32,
     vdelay: f32,
@@ -356,6 +561,42 @@ pub(super) struct Voice {
     arp: f64,
     atime: i32,
     alimit: i32,
+    fmphase: f32,
+    fmratio: f32,
+    fmindex: f32,
+    fmtime: i32,
+    fmlength: [i32; 2],
+    rphase: f32,
+    rratio: f32,
+    /// Velocity of the current note.
+    gain: f32,
+    /// Period a glide is heading for, zero when not gliding.
+    target: f64,
+    /// Factor applied to the period on every sample of a glide.
+    glide: f64,
+    /// Length of the anti-click ramps in samples.
+    ramp: i32,
+    /// Samples left in the crossfade from `tail`, the last sample before the note started.
+    fadein: i32,
+    tail: f32,
+    /// Samples left before the voice falls silent, zero when not fading out.
+    fadeout: i32,
+    last: f32,
+    /// Played live and not released yet, which holds the envelope at the sustain stage.
+    held: bool,
+    /// Envelope level the decay stage starts from.
+    level: f32,
+    /// Parameters changed while playing, each with the value it is heading for and the
+    /// smoothing time in samples.
+    smoothing: Vec<(&'static super::params::Param, f32, i32)>,
+    /// Ramps started by `[name from -> to over beats]` in the tune.
+    automation: Vec<Automation>,
+    /// Phase of each LFO, the value it holds when it is a sample and hold, and its output.
+    lfos: Vec<(f32, f32, f32)>,
+    /// Beats per minute that tempo-synced LFOs follow.
+    tempo: f32,
+    /// Extra voices playing the remaining pitches of a chord.
+    chord: Vec<Voice>,
 }
 
 impl Voice {
@@ -381,18 +622,24 @@ impl Voice {
 
     fn start(&mut self) {
         self.phase = 0;
-        let filter2 = f32::powf(self.params.lpf, 3.0) * 0.1;
-        let filter4 = 5.0 / (1.0 + f32::powf(self.params.resonance, 2.0) * 20.0) * (0.01 + filter2);
+        self.gain = 1.0;
+        self.target = 0.0;
+        self.fadeout = 0;
+        self.held = false;
+        self.level = 1.0;
+        self.lfos.clear();
+        let (filter2, filter4) = self.lowpass();
         self.filter = [
             0.0,
             0.0,
             filter2,
             1.0 + self.params.lsweep * 0.0001,
-            f32::min(filter4, 0.8),
+            filter4,
             0.0,
             f32::powf(self.params.hpf, 2.0) * 0.1,
             1.0 + self.params.hsweep * 0.0003,
         ];
+        self.svf = Default::default();
 
         self.vibe = 0.0;
         self.vspeed = f32::powf(self.params.vspeed, 2.0) * 0.01;
@@ -401,11 +648,7 @@ impl Voice {
         self.volume = 0.0;
         self.stage = 0;
         self.time = 0;
-        self.length = [
-            (self.params.attack * self.params.attack * 100000.0) as i32,
-            (self.params.sustain * self.params.sustain * 100000.0) as i32,
-            (self.params.decay * self.params.decay * 100000.0) as i32,
-        ];
+        self.length = self.lengths();
 
         let fphase = f32::powf(self.params.phase, 2.0) * 1020.0;
         self.fphase = if self.params.phase >= 0.0 {
@@ -424,18 +667,869 @@ impl Voice {
 
         self.phaser = Default::default();
         self.noise = rand::random::<[f32; 32]>().map(|r| 2.0 * r - 1.0); // array of random f32s // TODO: verify this is actually the same as the C version
+        self.lfsr = 1;
+        self.lfsrstep = 0;
+        self.spos = 0.0;
+
+        // ratios map 0..1 onto 0..16 times the carrier frequency, so 0.0625 is 1:1
+        self.fmphase = 0.0;
+        self.fmratio = self.params.fmratio * 16.0;
+        self.fmindex = self.params.fmindex * 8.0;
+        self.fmtime = 0;
+        self.fmlength = [
+            (self.params.fmattack * self.params.fmattack * 100000.0) as i32,
+            (self.params.fmdecay * self.params.fmdecay * 100000.0) as i32,
+        ];
+        self.rphase = 0.0;
+        self.rratio = self.params.rratio * 16.0;
 
         self.repeat = 0;
         let limit = (f32::powf(1.0 - self.params.repeat, 2.0) * 20000.0 + 32.0) as i32;
         self.limit = if self.params.repeat == 0.0 { 0 } else { limit };
         self.state = State::Play;
     }
+
+    fn lowpass(&self) -> (f32, f32) {
+        lowpass(self.params.lpf, self.params.resonance)
+    }
+
+    /// Lengths of the envelope stages in samples.
+    fn lengths(&self) -> [i32; 4] {
+        let length = |param: f32| (param * param * 100000.0) as i32;
+        if self.params.adsr {
+            // the sustain stage lasts until the note is released
+            [
+                length(self.params.attack),
+                length(self.params.decay),
+                i32::MAX,
+                length(self.params.release),
+            ]
+        } else {
+            [
+                length(self.params.attack),
+                length(self.params.sustain),
+                length(self.params.decay),
+                0,
+            ]
+        }
+    }
+
+    /// Changes a parameter in the middle of a note, gliding to the new value with a one-pole
+    /// filter whose time constant is `ramp` samples so that it doesn't step audibly. Toggles
+    /// switch at once.
+    fn set_param(&mut self, param: &'static super::params::Param, value: f32, ramp: i32) {
+        self.smoothing
+            .retain(|(other, ..)| other.name != param.name);
+        if param.name == "freq" && self.period > 0.0 {
+            // start from the pitch that is playing, which a note or slide may have moved
+            self.params.freq = f64::sqrt((100.0 / self.period - 0.001).max(0.0)) as f32;
+        }
+        if param.unit == super::params::Unit::Toggle || ramp <= 0 {
+            let from = param.get(&self.params);
+            param.set(&mut self.params, value);
+            self.follow(param.name, from);
+        } else {
+            self.smoothing.push((param, value, ramp));
+        }
+        for voice in &mut self.chord {
+            voice.set_param(param, value, ramp);
+        }
+    }
+
+    /// Swaps in a whole set of parameters in the middle of a note, which keeps its pitch.
+    fn set_params(&mut self, params: &Params) {
+        self.params = params.clone();
+        self.smoothing.clear();
+        for param in super::params::PARAMS {
+            if param.name != "freq" {
+                self.update(param.name);
+            }
+        }
+        for voice in &mut self.chord {
+            voice.set_params(params);
+        }
+    }
+
+    /// Moves the parameters set while playing one sample closer to their new values.
+    fn smooth(&mut self) {
+        let mut smoothing = std::mem::take(&mut self.smoothing);
+        smoothing.retain(|&(param, target, ramp)| {
+            let from = param.get(&self.params);
+            let value = from + (target - from) / ramp as f32;
+            let done = (target - value).abs() <= (param.max - param.min) * 0.0001;
+            param.set(&mut self.params, if done { target } else { value });
+            self.follow(param.name, from);
+            !done
+        });
+        self.smoothing = smoothing;
+    }
+
+    /// Moves the fields worked out from a parameter along with a change of it from `from`.
+    /// Fields that slides and sweeps keep moving during a note change by the same step or ratio
+    /// as the parameter, so what those did since the note started carries on.
+    fn follow(&mut self, name: &str, from: f32) {
+        let params = &self.params;
+        let period = |freq: f32| 100.0 / (freq as f64 * freq as f64 + 0.001);
+        let slide = |slide: f32| 1.0 - f64::powf(slide as f64, 3.0) * 0.01;
+        let hpf = |hpf: f32| f32::powf(hpf, 2.0) * 0.1;
+        match name {
+            "freq" => {
+                let ratio = period(params.freq) / period(from);
+                self.period *= ratio;
+                self.target *= ratio;
+            }
+            "slide" => self.slide += slide(params.slide) - slide(from),
+            "square" => self.square -= (params.square - from) * 0.5,
+            "lpf" | "resonance" => {
+                let (old, _) = match name {
+                    "lpf" => lowpass(from, params.resonance),
+                    _ => lowpass(params.lpf, from),
+                };
+                let (filter2, filter4) = self.lowpass();
+                self.filter[2] = if old > 0.0 {
+                    self.filter[2] * filter2 / old
+                } else {
+                    filter2
+                };
+                self.filter[4] = filter4;
+            }
+            "hpf" if hpf(from) > 0.0 => self.filter[6] *= hpf(params.hpf) / hpf(from),
+            _ => self.update(name),
+        }
+    }
+
+    /// Moves the automated parameters to where their ramps are at `ticks`.
+    fn automate(&mut self, ticks: f64) {
+        let mut automation = std::mem::take(&mut self.automation);
+        automation.retain(|ramp| {
+            let (value, done) = ramp.value(ticks);
+            ramp.param.set(&mut self.params, value);
+            self.update(ramp.param.name);
+            for voice in &mut self.chord {
+                ramp.param.set(&mut voice.params, value);
+                voice.update(ramp.param.name);
+            }
+            !done
+        });
+        self.automation = automation;
+    }
+
+    /// Brings the fields worked out from a parameter at the start of a note up to date with
+    /// it. Parameters that are read while playing need nothing, and the arpeggio, phaser and
+    /// repeat settings wait for the next note.
+    fn update(&mut self, name: &str) {
+        let params = &self.params;
+        match name {
+            "freq" => self.period = 100.0 / (params.freq as f64 * params.freq as f64 + 0.001),
+            "limit" => self.maxperiod = 100.0 / (params.limit as f64 * params.limit as f64 + 0.001),
+            "slide" => self.slide = 1.0 - f64::powf(params.slide as f64, 3.0) * 0.01,
+            "dslide" => self.dslide = -f64::powf(params.dslide as f64, 3.0) * 0.000001,
+            "square" => self.square = 0.5 - params.square * 0.5,
+            "sweep" => self.sweep = -params.sweep * 0.00005,
+            "vibe" => self.vdelay = params.vibe * 0.5,
+            "vspeed" => self.vspeed = f32::powf(params.vspeed, 2.0) * 0.01,
+            "lpf" | "resonance" => (self.filter[2], self.filter[4]) = self.lowpass(),
+            "lsweep" => self.filter[3] = 1.0 + params.lsweep * 0.0001,
+            "hpf" => self.filter[6] = f32::powf(params.hpf, 2.0) * 0.1,
+            "hsweep" => self.filter[7] = 1.0 + params.hsweep * 0.0003,
+            "fmratio" => self.fmratio = params.fmratio * 16.0,
+            "fmindex" => self.fmindex = params.fmindex * 8.0,
+            "fmattack" | "fmdecay" => {
+                self.fmlength = [
+                    (params.fmattack * params.fmattack * 100000.0) as i32,
+                    (params.fmdecay * params.fmdecay * 100000.0) as i32,
+                ]
+            }
+            "rratio" => self.rratio = params.rratio * 16.0,
+            "attack" | "sustain" | "decay" | "release" | "adsr" => self.length = self.lengths(),
+            _ => {}
+        }
+    }
+
+    fn apply_fx(&mut self, fx: &Fx) {
+        match fx.command {
+            FxCommand::Param(param) => {
+                let current = param.get(&self.params);
+                let value = match fx.r#mod {
+                    '+' => current + fx.val,
+                    '-' => current - fx.val,
+                    _ => fx.val,
+                };
+                self.automation.retain(|ramp| ramp.param.name != param.name);
+                match fx.over {
+                    Some(beats) if beats > 0.0 => {
+                        // the note carrying the fx hasn't moved `nextnote` on yet
+                        let start = self.nextnote[0] as f64;
+                        self.automation.push(Automation {
+                            param,
+                            from: fx.from.unwrap_or(current),
+                            to: value.clamp(param.min, param.max),
+                            start,
+                            end: start + beats as f64 * TICKS_PER_BEAT as f64,
+                            exponential: fx.exponential,
+                        });
+                    }
+                    _ => param.set(&mut self.params, value),
+                }
+            }
+            FxCommand::Duty => {
+                if let super::Waveform::Pulse(duty) = &mut self.params.r#type {
+                    let ratio = match fx.r#mod {
+                        '+' => duty.ratio() + fx.val,
+                        '-' => duty.ratio() - fx.val,
+                        _ => fx.val,
+                    };
+                    *duty = super::Duty::nearest(ratio);
+                }
+            }
+            // shared by all tracks, see `Clock::apply_fx`
+            FxCommand::Tempo | FxCommand::Time(..) => {}
+            // needs the registry, see `Rustaphone::synth`
+            FxCommand::Instrument(_) => {}
+        }
+    }
+
+    fn play_note(&mut self, note: &Note, polyphony: usize, semitones: i32, ramp: i32) {
+        let mut freq = self.params.freq;
+        if note.tone != 'n' {
+            freq = note.freq(semitones);
+        }
+        // the old chord fades out alongside the new one
+        let rest = freq == 0.0;
+        for voice in &mut self.chord {
+            if rest {
+                voice.end_note(ramp);
+            } else {
+                voice.fade_out(ramp);
+            }
+        }
+        self.chord.retain(|voice| voice.state == State::Play);
+        // effects on rests set up the notes that follow them
+        for fx in &note.fx {
+            self.apply_fx(fx);
+        }
+        if rest {
+            self.end_note(ramp);
+            if self.state == State::Stop {
+                self.period = 0.0;
+            }
+            return;
+        }
+
+        let from = self.period;
+        let playing = self.state == State::Play && self.fadeout == 0;
+        if playing && (note.legato || self.params.legato) {
+            // only the pitch moves, the envelope and phase keep running
+            self.period = 100.0 / (freq * freq + 0.001) as f64;
+        } else {
+            self.trigger(freq);
+            self.fade_in(ramp);
+        }
+        self.glide_from(from);
+        self.gain = note.gain();
+
+        for &(tone, octave) in note.chord.iter().take(polyphony - 1) {
+            let (tone, octave) = transpose(tone, octave, semitones);
+            let freq = tone_freq(tone, octave);
+            if freq == 0.0 {
+                continue;
+            }
+
+            let mut voice = Voice {
+                params: self.params.clone(),
+                smoothing: self.smoothing.clone(),
+                ..Default::default()
+            };
+            voice.trigger(freq);
+            voice.fade_in(ramp);
+            voice.gain = self.gain;
+            self.chord.push(voice);
+        }
+    }
+
+    /// Lets go of a held note, decaying from wherever the envelope is.
+    fn release(&mut self, ramp: i32) {
+        self.held = false;
+        let last = if self.params.adsr { 3 } else { 2 };
+        if self.state == State::Stop || self.stage >= last {
+            return;
+        }
+        if self.length[last as usize] > 0 {
+            self.level = self.volume.min(1.0);
+            self.stage = last;
+            self.time = 0;
+        } else {
+            self.fade_out(ramp);
+        }
+    }
+
+    /// Ends the current note at a rest or the end of the tune, which is where an ADSR
+    /// envelope starts its release.
+    fn end_note(&mut self, ramp: i32) {
+        if self.params.adsr {
+            self.release(ramp);
+        } else {
+            self.fade_out(ramp);
+        }
+    }
+
+    /// Crossfades from the last sample played into the note that was just triggered.
+    fn fade_in(&mut self, ramp: i32) {
+        self.ramp = ramp;
+        self.fadein = ramp;
+        self.tail = self.last;
+    }
+
+    /// Ramps the voice down to silence instead of cutting it off.
+    fn fade_out(&mut self, ramp: i32) {
+        if self.state == State::Play && ramp > 0 {
+            if self.fadeout == 0 {
+                self.ramp = ramp;
+                self.fadeout = ramp;
+            }
+        } else {
+            self.state = State::Stop;
+        }
+    }
+
+    /// Applies the anti-click ramps to a rendered sample.
+    fn declick(&mut self, mut sample: f32) -> f32 {
+        if self.fadein > 0 {
+            let t = 1.0 - self.fadein as f32 / self.ramp as f32;
+            sample = self.tail + (sample - self.tail) * t;
+            self.fadein -= 1;
+        }
+        if self.fadeout > 0 {
+            sample *= self.fadeout as f32 / self.ramp as f32;
+            self.fadeout -= 1;
+            if self.fadeout == 0 {
+                self.state = State::Stop;
+            }
+        }
+        self.last = sample;
+
+        sample
+    }
+
+    /// Slides from the period of the previous note to the current one over the glide time.
+    fn glide_from(&mut self, from: f64) {
+        let to = self.period;
+        let length = (self.params.glide * self.params.glide * 100000.0) as f64;
+        if from > 0.0 && length >= 1.0 && from != to {
+            self.period = from;
+            self.target = to;
+            self.glide = f64::powf(to / from, 1.0 / length);
+        } else {
+            self.target = 0.0;
+        }
+    }
+
+    fn trigger(&mut self, freq: f32) {
+        self.reset();
+        self.start();
+        self.period = 100.0 / (freq * freq + 0.001) as f64;
+    }
+
+    /// Renders one sample of this voice, before the master volume is applied.
+    fn synth(&mut self, sample_rate: u32) -> f32 {
+        if self.state == State::Stop {
+            self.last = 0.0;
+            return 0.0;
+        }
+        if !self.smoothing.is_empty() {
+            self.smooth();
+        }
+        let modulation = self.modulate(sample_rate);
+
+        self.repeat += 1;
+        if self.limit != 0 && self.repeat >= self.limit {
+            self.repeat = 0;
+            self.reset();
+        }
+
+        self.atime += 1;
+        if self.alimit != 0 && self.atime >= self.alimit {
+            self.alimit = 0;
+            self.period *= self.arp;
+        }
+
+        self.slide += self.dslide;
+        self.period *= self.slide;
+        if self.target > 0.0 {
+            self.period *= self.glide;
+            if (self.glide > 1.0) == (self.period >= self.target) {
+                self.period = self.target;
+                self.target = 0.0;
+            }
+        }
+        if self.period > self.maxperiod {
+            self.period = self.maxperiod;
+            if self.params.limit > 0.0 {
+                self.state = State::Stop;
+            }
+        }
+
+        let mut rfperiod = self.period as f32;
+        if self.vdelay > 0.0 {
+            self.vibe += self.vspeed;
+            rfperiod = self.period as f32 * (1.0 + f32::sin(self.vibe) * self.vdelay);
+        }
+        if modulation.pitch != 0.0 {
+            rfperiod *= f32::powf(2.0, -modulation.pitch / 12.0);
+        }
+
+        let mut period = rfperiod as i32;
+        if period < 8 {
+            period = 8;
+        }
+        self.square += self.sweep;
+        self.square = self.square.clamp(0.0, 0.5);
+        let square = (self.square + modulation.duty).clamp(0.0, 0.5);
+
+        let stages = if self.params.adsr { 4 } else { 3 };
+        self.time += 1;
+        while self.time >= self.length[self.stage as usize] {
+            if self.held && self.stage == 1 && !self.params.adsr {
+                // held notes sustain until they are released
+                self.time = self.length[1];
+                break;
+            }
+            self.time = 0;
+            self.stage += 1;
+            if self.stage == stages {
+                self.state = State::Stop;
+                break; // TODO: is this correct?
+            }
+        }
+
+        let progress = self.time as f32 / self.length[self.stage.min(3) as usize].max(1) as f32;
+        match (self.params.adsr, self.stage) {
+            (_, 0) => {
+                self.volume = self.time as f32 / self.length[0] as f32;
+            }
+            (false, 1) => {
+                self.volume = 1.0 + (1.0 - progress) * 2.0 * self.params.punch;
+            }
+            (false, 2) | (true, 3) => {
+                self.volume = (1.0 - progress) * self.level;
+            }
+            (true, 1) => {
+                let slevel = self.params.slevel;
+                self.volume = slevel + (1.0 - progress) * (1.0 - slevel + 2.0 * self.params.punch);
+            }
+            (true, 2) => {
+                self.volume = self.params.slevel;
+            }
+            _ => {}
+        }
+
+        self.fphase += self.dphase;
+        self.iphase = ((self.fphase + modulation.phaser * 1020.0) as i32).abs();
+        if self.iphase > 1023 {
+            self.iphase = 1023;
+        }
+
+        if self.filter[7] != 0.0 {
+            self.filter[6] *= self.filter[7];
+            self.filter[6] = self.filter[6].clamp(0.00001, 0.1);
+        }
+
+        // modulation depth in cycles of the carrier
+        let fmdepth = if self.fmindex > 0.0 {
+            self.fmtime += 1;
+            self.fmindex * self.fm_envelope() / (2.0 * core::f32::consts::PI)
+        } else {
+            0.0
+        };
+
+        let svf = (self.params.filter != super::FilterMode::Legacy).then(|| {
+            // the envelope sweeps the cutoff by up to `fenv` octaves
+            let octaves = self.params.fenv * self.volume + modulation.cutoff;
+            let cutoff = self.params.cutoff * f32::powf(2.0, octaves);
+            SvfCoefficients::new(cutoff, self.params.q, sample_rate as f32 * 8.0)
+        });
+
+        // scaling the damping with the square root of the coefficient keeps the shape of the
+        // response, so the cutoff moves by as many octaves as the coefficient's square root
+        let lpf = f32::powf(2.0, modulation.cutoff);
+
+        let mut ssample = 0.0;
+        for _ in 0..8 {
+            self.phase += 1;
+            if self.phase >= period {
+                self.phase %= period;
+                if self.params.r#type == super::Waveform::Noise {
+                    for i in 0..32 {
+                        self.noise[i] = rand::random::<f32>() * 2.0 - 1.0;
+                        // TODO: verify this is actually the same as the C version.
+                        // TODO: Init array without loop as above.
+                    }
+                }
+            }
+            if let super::Waveform::PeriodicNoise(mode) = self.params.r#type {
+                // clock the LFSR 32 times per period, like the white noise table
+                let step = self.phase * 32 / period;
+                if step != self.lfsrstep {
+                    self.lfsrstep = step;
+                    self.clock_lfsr(mode);
+                }
+            }
+
+            let mut fp = self.phase as f32 / period as f32;
+            if fmdepth > 0.0 {
+                self.fmphase = (self.fmphase + self.fmratio / period as f32).fract();
+                fp = (fp + fmdepth * f32::sin(self.fmphase * 2.0 * core::f32::consts::PI))
+                    .rem_euclid(1.0);
+            }
+            let mut sample = match &self.params.r#type {
+                super::Waveform::Square => {
+                    if fp < square {
+                        0.5
+                    } else {
+                        -0.5
+                    }
+                }
+                super::Waveform::Sawtooth => 1.0 - fp * 2.0,
+                super::Waveform::Sine => f32::sin(fp * 2.0 * core::f32::consts::PI),
+                super::Waveform::Noise => self.noise[(self.phase * 32 / period) as usize],
+                super::Waveform::Triangle => 1.0 - f32::abs(fp - 0.5) * 4.0,
+                super::Waveform::NesTriangle => {
+                    let step = (fp * 32.0) as i32;
+                    let level = if step < 16 { 15 - step } else { step - 16 };
+                    level as f32 / 7.5 - 1.0
+                }
+                super::Waveform::Pulse(duty) => {
+                    if fp < duty.ratio() + modulation.duty {
+                        0.5
+                    } else {
+                        -0.5
+                    }
+                }
+                super::Waveform::PeriodicNoise(_) => {
+                    if self.lfsr & 1 == 0 {
+                        0.5
+                    } else {
+                        -0.5
+                    }
+                }
+                super::Waveform::Wavetable(table) => table.sample(fp),
+                super::Waveform::Sample(sample) => {
+                    let value = sample.sample(self.spos);
+                    self.spos = sample.advance(self.spos, sample.step(rfperiod));
+                    value
+                }
+            };
+
+            if self.params.ring > 0.0 {
+                self.rphase = (self.rphase + self.rratio / period as f32).fract();
+                let modulator = f32::sin(self.rphase * 2.0 * core::f32::consts::PI);
+                sample *= 1.0 - self.params.ring + self.params.ring * modulator;
+            }
+
+            if let Some(svf) = svf {
+                sample = self.svf[0].process(sample, svf, self.params.filter);
+                if self.params.slope == super::FilterSlope::Db24 {
+                    sample = self.svf[1].process(sample, svf, self.params.filter);
+                }
+            } else {
+                let pp = self.filter[0];
+                self.filter[2] *= self.filter[3];
+                self.filter[2] = self.filter[2].clamp(0.0, 0.1);
+                if self.params.lpf != 1.0 {
+                    let w = f32::min(self.filter[2] * lpf * lpf, 0.1);
+                    self.filter[1] += (sample - self.filter[0]) * w;
+                    self.filter[1] -= self.filter[1] * f32::min(self.filter[4] * lpf, 0.8);
+                } else {
+                    self.filter[0] = sample;
+                    self.filter[1] = 0.0;
+                }
+                self.filter[0] += self.filter[1];
+
+                self.filter[5] += self.filter[0] - pp;
+                self.filter[5] -= self.filter[5] * self.filter[6];
+                sample = self.filter[5];
+            }
+
+            self.phaser.0[(self.phasex & 1023) as usize] = sample;
+            sample += self.phaser.0[((self.phasex - self.iphase + 1024) & 1023) as usize];
+            self.phasex = (self.phasex + 1) & 1023;
+
+            ssample += sample * self.volume;
+        }
+        let volume = self.params.volume * (1.0 + modulation.volume).max(0.0);
+        self.declick(ssample / 8.0 * 2.0 * volume * self.gain)
+    }
+
+    /// Advances the LFOs by one sample and adds up what the modulation matrix routes to each
+    /// target.
+    fn modulate(&mut self, sample_rate: u32) -> Modulation {
+        let mut modulation = Modulation::default();
+        if self.params.modulation.is_empty() {
+            return modulation;
+        }
+
+        let random = || rand::random::<f32>() * 2.0 - 1.0;
+        self.lfos
+            .resize_with(self.params.lfos.len(), || (0.0, random(), 0.0));
+        for (i, &(shape, rate)) in self.params.lfos.iter().enumerate() {
+            let (phase, held, value) = &mut self.lfos[i];
+            *value = match shape {
+                super::LfoShape::Sine => f32::sin(*phase * 2.0 * core::f32::consts::PI),
+                super::LfoShape::Triangle => 1.0 - f32::abs(*phase - 0.5) * 4.0,
+                super::LfoShape::Square if *phase < 0.5 => 1.0,
+                super::LfoShape::Square => -1.0,
+                super::LfoShape::SampleAndHold => *held,
+            };
+
+            let hz = match rate {
+                super::LfoRate::Hz(hz) => hz,
+                super::LfoRate::Beats(beats) if beats > 0.0 => self.tempo / 60.0 / beats,
+                super::LfoRate::Beats(_) => 0.0,
+            };
+            *phase += hz / sample_rate as f32;
+            if *phase >= 1.0 {
+                *phase = phase.fract();
+                *held = random();
+            }
+        }
+
+        for &(source, target, depth) in &self.params.modulation {
+            let value = match source {
+                super::ModSource::Lfo(i) => self.lfos.get(i).map_or(0.0, |lfo| lfo.2),
+                super::ModSource::Envelope => self.volume.min(1.0),
+            } * depth;
+            match target {
+                super::ModTarget::Pitch => modulation.pitch += value,
+                super::ModTarget::Volume => modulation.volume += value,
+                super::ModTarget::Cutoff => modulation.cutoff += value,
+                super::ModTarget::Duty => modulation.duty += value,
+                super::ModTarget::Phaser => modulation.phaser += value,
+            }
+        }
+
+        modulation
+    }
+
+    /// Returns the level of the modulation index envelope: a linear attack to full depth,
+    /// followed by a linear decay to silence (or a hold if there is no decay).
+    fn fm_envelope(&self) -> f32 {
+        let [attack, decay] = self.fmlength;
+        if self.fmtime < attack {
+            self.fmtime as f32 / attack as f32
+        } else if decay == 0 {
+            1.0
+        } else {
+            f32::max(1.0 - (self.fmtime - attack) as f32 / decay as f32, 0.0)
+        }
+    }
+
+    fn clock_lfsr(&mut self, mode: super::NoiseMode) {
+        let tap = match mode {
+            super::NoiseMode::Long => 1,
+            super::NoiseMode::Short => 6,
+        };
+        let feedback = (self.lfsr ^ (self.lfsr >> tap)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+    }
+}
+
+/// Voices played directly with note on and off events instead of a tune.
+pub(super) struct Live {
+    voices: Vec<Voice>,
+    generations: Vec<u32>,
+    /// Generation handed to the next note, also tells which voice is the oldest.
+    next: u32,
+    volume: f32,
+}
+
+impl Live {
+    pub fn new(volume: f32) -> Self {
+        Live {
+            voices: (0..MAX_LIVE_VOICES).map(|_| Voice::default()).collect(),
+            generations: vec![0; MAX_LIVE_VOICES],
+            next: 1,
+            volume,
+        }
+    }
+
+    /// Starts a note on a free voice, or the oldest one when all are busy, and returns the
+    /// voice and the generation that identifies this note on it.
+    pub fn note_on(
+        &mut self,
+        instrument: &super::Instrument,
+        pitch: u8,
+        velocity: u8,
+        ramp: i32,
+    ) -> (usize, u32) {
+        let slot = self
+            .voices
+            .iter()
+            .position(|voice| voice.state == State::Stop)
+            .unwrap_or_else(|| {
+                (0..MAX_LIVE_VOICES)
+                    .min_by_key(|&slot| self.generations[slot])
+                    .unwrap()
+            });
+
+        let voice = &mut self.voices[slot];
+        voice.params = instrument.params.clone();
+        voice.trigger(midi_freq(pitch.min(127)));
+        voice.fade_in(ramp);
+        voice.gain = velocity.min(127) as f32 / DEFAULT_VELOCITY as f32;
+        voice.held = true;
+        voice.tempo = LIVE_TEMPO;
+
+        let generation = self.next;
+        self.next += 1;
+        self.generations[slot] = generation;
+
+        (slot, generation)
+    }
+
+    /// Releases a note, unless its voice has been taken over by a newer one since.
+    pub fn note_off(&mut self, slot: usize, generation: u32, ramp: i32) -> bool {
+        if self.generations.get(slot) != Some(&generation) {
+            return false;
+        }
+        let voice = &mut self.voices[slot];
+        let sounding = voice.state == State::Play;
+        voice.release(ramp);
+
+        sounding
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.voices.iter().all(|voice| voice.state == State::Stop)
+    }
+
+    pub fn synth(&mut self, sample_rate: u32) -> f32 {
+        let mut sample = 0.0;
+        for voice in &mut self.voices {
+            sample += voice.synth(sample_rate);
+        }
+
+        (sample * self.volume).clamp(-1.0, 1.0)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TempoRamp {
+    from: f64,
+    to: f64,
+    start: f64,
+    end: f64,
+}
+
+/// Playback position and tempo, shared by all tracks so they stay in sync.
+#[derive(Clone, Default)]
+struct Clock {
+    ticks: f64,
+    bpm: f64,
+    ramp: Option<TempoRamp>,
+    /// Beats per bar and the note value of a beat.
+    meter: (u8, u8),
+    bar: u32,
+    barstart: f64,
+    /// Tick and track of the last tempo or time directive.
+    directive: Option<(u64, usize)>,
+}
+
+impl Clock {
+    fn start(&mut self, tempo: i32) {
+        *self = Clock {
+            bpm: tempo as f64,
+            meter: (4, 4),
+            ..Default::default()
+        };
+    }
+
+    fn bar_ticks(&self) -> f64 {
+        (TICKS_PER_WHOLE as u64 * self.meter.0 as u64 / self.meter.1 as u64) as f64
+    }
+
+    /// Applies a tempo or meter change scheduled at `tick` in `track`. Directives at the same
+    /// tick are only taken from the first track that has any, so every track may carry them.
+    fn apply_fx(&mut self, fx: &Fx, tick: u64, track: usize) {
+        if matches!(fx.command, FxCommand::Tempo | FxCommand::Time(..)) {
+            match self.directive {
+                Some((at, from)) if at == tick && from != track => return,
+                _ => self.directive = Some((tick, track)),
+            }
+        }
+        let tick = tick as f64;
+        match fx.command {
+            FxCommand::Tempo => {
+                let val = fx.val as f64;
+                let bpm = match fx.r#mod {
+                    '+' => self.bpm + val,
+                    '-' => self.bpm - val,
+                    // a bare negative value reads as a change, not as a tempo
+                    _ if val < 0.0 => self.bpm + val,
+                    _ => val,
+                }
+                .clamp(1.0, 1000.0);
+
+                match fx.over {
+                    Some(beats) if beats > 0.0 => {
+                        self.ramp = Some(TempoRamp {
+                            from: fx
+                                .from
+                                .map_or(self.bpm, |from| from.clamp(1.0, 1000.0) as f64),
+                            to: bpm,
+                            start: tick,
+                            end: tick + beats as f64 * TICKS_PER_BEAT as f64,
+                        })
+                    }
+                    _ => {
+                        self.bpm = bpm;
+                        self.ramp = None;
+                    }
+                }
+            }
+            FxCommand::Time(beats, unit) if beats > 0 && unit > 0 => {
+                // a new meter always starts a new bar
+                if tick > self.barstart {
+                    self.bar += 1;
+                }
+                self.barstart = tick;
+                self.meter = (beats, unit);
+            }
+            _ => {}
+        }
+    }
+
+    fn advance(&mut self, sample_rate: u32) {
+        if let Some(ramp) = self.ramp {
+            let t = ((self.ticks - ramp.start) / (ramp.end - ramp.start)).clamp(0.0, 1.0);
+            self.bpm = ramp.from + (ramp.to - ramp.from) * t;
+            if t >= 1.0 {
+                self.ramp = None;
+            }
+        }
+
+        self.ticks += self.bpm / 60.0 * TICKS_PER_BEAT as f64 / sample_rate as f64;
+
+        let bar = self.bar_ticks();
+        while self.ticks >= self.barstart + bar {
+            self.barstart += bar;
+            self.bar += 1;
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Rustaphone {
     tempo: i32,
     volume: f32,
+    /// Semitones added to every note as it starts.
+    transpose: i32,
+    /// Named instruments that `[inst name]` switches to.
+    instruments: Vec<(String, Params)>,
+    /// Length of the anti-click ramps in seconds.
+    declick: f32,
+    /// Samples played since `stop` was called.
+    stopping: Option<i32>,
+    clock: Clock,
     voices: [Option<Voice>; MAX_TRACKS],
     state: State,
 }
@@ -445,21 +1539,35 @@ impl Rustaphone {
         Rustaphone {
             tempo,
             volume,
+            transpose: 0,
+            instruments: Vec::new(),
+            declick: DECLICK_TIME,
+            stopping: None,
+            clock: Clock::default(),
             voices: [UNUSED_VOICE; MAX_TRACKS],
             state: State::Stop,
         }
     }
 
-    pub fn clear(&mut self) {
-        for i in 0..MAX_TRACKS {
-            self.set_track_at(None, i);
-        }
-    }
-
     pub fn tempo(&mut self, tempo: i32) {
         self.tempo = tempo;
     }
 
+    pub fn transpose(&mut self, semitones: i32) {
+        self.transpose = semitones;
+    }
+
+    pub fn has_instrument(&self, name: &str) -> bool {
+        self.instruments.iter().any(|(n, _)| n == name)
+    }
+
+    pub fn add_instrument(&mut self, name: &str, instrument: super::Instrument) {
+        match self.instruments.iter_mut().find(|(n, _)| n == name) {
+            Some((_, params)) => *params = instrument.params,
+            None => self.instruments.push((name.to_string(), instrument.params)),
+        }
+    }
+
     pub fn play(&mut self) {
         for i in 0..MAX_TRACKS {
             if let Some(voice) = &mut self.voices[i] {
@@ -467,23 +1575,60 @@ impl Rustaphone {
                     voice.params = track.params.clone();
                     voice.reset();
                     voice.start();
-                    voice.frames = 0;
                     voice.nextnote = [0; 2];
+                    voice.chord.clear();
+                    voice.automation.clear();
                 }
             }
         }
 
+        self.clock.start(self.tempo);
+        self.stopping = None;
         self.state = State::Play;
     }
 
+    /// Fades out over the declick time, [`Rustaphone::is_done`] turns true once it's silent.
     pub fn stop(&mut self) {
-        self.state = State::Stop;
+        if self.state == State::Play && self.stopping.is_none() {
+            self.stopping = Some(0);
+        }
+    }
+
+    pub fn declick(&mut self, seconds: f32) {
+        self.declick = seconds.max(0.0);
     }
 
     pub fn is_done(&self) -> bool {
         self.state == State::Stop
     }
 
+    /// Swaps the parameters of a playing track, see [`super::Mixer::set_instrument`].
+    pub fn set_params(&mut self, track: usize, params: &Params) -> bool {
+        let Some(Some(voice)) = self.voices.get_mut(track) else {
+            return false;
+        };
+        voice.set_params(params);
+
+        true
+    }
+
+    /// Changes a parameter of a playing track, smoothed over the declick time.
+    pub fn set_param(
+        &mut self,
+        track: usize,
+        param: &'static super::params::Param,
+        value: f32,
+        sample_rate: u32,
+    ) -> bool {
+        let ramp = (self.declick * sample_rate as f32) as i32;
+        let Some(Some(voice)) = self.voices.get_mut(track) else {
+            return false;
+        };
+        voice.set_param(param, value, ramp);
+
+        true
+    }
+
     pub fn add_track(&mut self, track: Track) {
         for i in 0..MAX_TRACKS {
             if self.voices[i].is_none() {
@@ -494,6 +1639,21 @@ impl Rustaphone {
     }
 
     pub fn synth(&mut self, sample_rate: u32, allsample: &mut f32) {
+        if self.state == State::Stop {
+            return;
+        }
+
+        let ramp = (self.declick * sample_rate as f32) as i32;
+        let mut gain = 1.0;
+        if let Some(time) = &mut self.stopping {
+            if *time >= ramp {
+                self.state = State::Stop;
+                return;
+            }
+            gain = 1.0 - *time as f32 / ramp as f32;
+            *time += 1;
+        }
+
         let mut moreframes = 0;
 
         for t in 0..MAX_TRACKS {
@@ -501,201 +1661,75 @@ impl Rustaphone {
                 continue;
             };
 
-            let Some(track) = a.track.clone() else {
+            let Some(track) = a.track.take() else {
                 continue;
             };
 
             if !track.notes.is_empty() {
-                if a.frames == a.nextnote[0] {
-                    if a.nextnote[1] < track.notes.len() as i32 {
-                        let note = &track.notes[a.nextnote[1] as usize];
-                        let mut freq = a.params.freq;
-                        if note.tone != 'n' {
-                            freq = note.freq();
+                let len = track.notes.len() as u64;
+                if self.clock.ticks >= a.nextnote[0] as f64 {
+                    if let Some(start) = track.loop_start.filter(|&i| i < track.notes.len()) {
+                        if a.nextnote[1] == len {
+                            a.nextnote[1] = start as u64;
                         }
-                        if freq == 0.0 {
-                            a.period = 0.0;
-                            a.state = State::Stop;
-                        } else {
-                            let note = &track.notes[a.nextnote[1] as usize];
-                            for fx in &note.fx {
-                                match fx.command {
-                                    FxCommand::Volume => fx!(fx, a, volume),
-                                    FxCommand::Punch => fx!(fx, a, punch),
-                                    FxCommand::Attack => fx!(fx, a, attack),
-                                    FxCommand::Sustain => fx!(fx, a, sustain),
-                                    FxCommand::Decay => fx!(fx, a, decay),
-                                    FxCommand::Square => fx!(fx, a, square),
-                                    FxCommand::Sweep => fx!(fx, a, sweep),
-                                    FxCommand::Vibe => fx!(fx, a, vibe),
-                                    FxCommand::VSpeed => fx!(fx, a, vspeed),
-                                    FxCommand::VDelay => fx!(fx, a, vdelay),
-                                    FxCommand::Lpf => fx!(fx, a, lpf),
-                                    FxCommand::LSweep => fx!(fx, a, lsweep),
-                                    FxCommand::Resonance => fx!(fx, a, resonance),
-                                    FxCommand::Hpf => fx!(fx, a, hpf),
-                                    FxCommand::HSweep => fx!(fx, a, hsweep),
-                                    FxCommand::Arp => fx!(fx, a, arp),
-                                    FxCommand::ASpeed => fx!(fx, a, aspeed),
-                                    FxCommand::Phase => fx!(fx, a, phase),
-                                    FxCommand::PSweep => fx!(fx, a, psweep),
-                                    FxCommand::Repeat => fx!(fx, a, repeat),
+                    }
+                    if a.nextnote[1] < len {
+                        let note = &track.notes[a.nextnote[1] as usize];
+                        for fx in &note.fx {
+                            if let FxCommand::Instrument(name) = &fx.command {
+                                // unknown names keep the current instrument
+                                if let Some((_, params)) =
+                                    self.instruments.iter().find(|(n, _)| n == name)
+                                {
+                                    a.params = params.clone();
                                 }
                             }
-
-                            a.reset();
-                            a.start();
-                            a.period = 100.0 / (freq * freq + 0.001) as f64;
+                            self.clock.apply_fx(fx, a.nextnote[0], t);
+                        }
+                        a.play_note(note, track.polyphony, self.transpose, ramp);
+
+                        // notes are scheduled at absolute ticks, so rounding never adds up
+                        a.nextnote[0] += note.duration as u64;
+                    } else if a.nextnote[1] == len {
+                        a.end_note(ramp);
+                        for voice in &mut a.chord {
+                            voice.end_note(ramp);
                         }
-
-                        a.nextnote[0] += (sample_rate as f32 / (self.tempo as f32 / 60.0)
-                            * (4.0 / note.duration as f32))
-                            as i32;
                     }
 
                     a.nextnote[1] += 1;
                 }
-                if a.nextnote[1] <= track.notes.len() as i32 {
+                // an ADSR release may ring on after the last note
+                if a.nextnote[1] <= len || (a.params.adsr && a.state == State::Play) {
                     moreframes += 1;
                 }
             } else {
                 moreframes += 1;
             }
 
-            a.frames += 1;
+            a.track = Some(track);
 
-            if a.state == State::Stop {
-                continue;
+            if !a.automation.is_empty() {
+                a.automate(self.clock.ticks);
             }
-
-            a.repeat += 1;
-            if a.limit != 0 && a.repeat >= a.limit {
-                a.repeat = 0;
-                a.reset();
+            a.tempo = self.clock.bpm as f32;
+            for voice in &mut a.chord {
+                voice.tempo = a.tempo;
             }
-
-            a.atime += 1;
-            if a.alimit != 0 && a.atime >= a.alimit {
-                a.alimit = 0;
-                a.period *= a.arp;
-            }
-
-            a.slide += a.dslide;
-            a.period *= a.slide;
-            if a.period > a.maxperiod {
-                a.period = a.maxperiod;
-                if a.params.limit > 0.0 {
-                    a.state = State::Stop;
-                }
-            }
-
-            let mut rfperiod = a.period as f32;
-            if a.vdelay > 0.0 {
-                a.vibe += a.vspeed;
-                rfperiod = a.period as f32 * (1.0 + f32::sin(a.vibe) * a.vdelay);
-            }
-
-            let mut period = rfperiod as i32;
-            if period < 8 {
-                period = 8;
-            }
-            a.square += a.sweep;
-            a.square = a.square.clamp(0.0, 0.5);
-
-            a.time += 1;
-            while a.time >= a.length[a.stage as usize] {
-                a.time = 0;
-                a.stage += 1;
-                if a.stage == 3 {
-                    a.state = State::Stop;
-                    break; // TODO: is this correct?
-                }
-            }
-
-            match a.stage {
-                0 => {
-                    a.volume = a.time as f32 / a.length[0] as f32;
-                }
-                1 => {
-                    a.volume =
-                        1.0 + (1.0 - a.time as f32 / a.length[1] as f32) * 2.0 * a.params.punch;
-                }
-                2 => {
-                    a.volume = 1.0 - a.time as f32 / a.length[2] as f32;
-                }
-                _ => {}
-            }
-
-            a.fphase += a.dphase;
-            a.iphase = (a.fphase as i32).abs();
-            if a.iphase > 1023 {
-                a.iphase = 1023;
-            }
-
-            if a.filter[7] != 0.0 {
-                a.filter[6] *= a.filter[7];
-                a.filter[6] = a.filter[6].clamp(0.00001, 0.1);
-            }
-
-            let mut ssample = 0.0;
-            for _ in 0..8 {
-                a.phase += 1;
-                if a.phase >= period {
-                    a.phase %= period;
-                    if a.params.r#type == super::Waveform::Noise {
-                        for i in 0..32 {
-                            a.noise[i] = rand::random::<f32>() * 2.0 - 1.0;
-                            // TODO: verify this is actually the same as the C version.
-                            // TODO: Init array without loop as above.
-                        }
-                    }
-                }
-
-                let fp = a.phase as f32 / period as f32;
-                let mut sample = match a.params.r#type {
-                    super::Waveform::Square => {
-                        if fp < a.square {
-                            0.5
-                        } else {
-                            -0.5
-                        }
-                    }
-                    super::Waveform::Sawtooth => 1.0 - fp * 2.0,
-                    super::Waveform::Sine => f32::sin(fp * 2.0 * core::f32::consts::PI),
-                    super::Waveform::Noise => a.noise[(a.phase * 32 / period) as usize],
-                };
-
-                let pp = a.filter[0];
-                a.filter[2] *= a.filter[3];
-                a.filter[2] = a.filter[2].clamp(0.0, 0.1);
-                if a.params.lpf != 1.0 {
-                    a.filter[1] += (sample - a.filter[0]) * a.filter[2];
-                    a.filter[1] -= a.filter[1] * a.filter[4];
-                } else {
-                    a.filter[0] = sample;
-                    a.filter[1] = 0.0;
-                }
-                a.filter[0] += a.filter[1];
-
-                a.filter[5] += a.filter[0] - pp;
-                a.filter[5] -= a.filter[5] * a.filter[6];
-                sample = a.filter[5];
-
-                a.phaser.0[(a.phasex & 1023) as usize] = sample;
-                sample += a.phaser.0[((a.phasex - a.iphase + 1024) & 1023) as usize];
-                a.phasex = (a.phasex + 1) & 1023;
-
-                ssample += sample * a.volume;
+            let mut ssample = a.synth(sample_rate);
+            for voice in &mut a.chord {
+                ssample += voice.synth(sample_rate);
             }
-            ssample = ssample / 8.0 * self.volume;
-            ssample *= 2.0 * a.params.volume;
+            ssample *= self.volume * gain;
 
             ssample = ssample.clamp(-1.0, 1.0);
             *allsample += ssample;
         }
 
+        self.clock.advance(sample_rate);
+
         if moreframes == 0 {
-            self.state = State::Stop;
+            self.stop();
         }
     }
 
@@ -711,8 +1745,10 @@ impl Rustaphone {
                     track,
                     params,
                     state: State::Stop,
-                    frames: 0,
                     nextnote: [0; 2],
+                    chord: Vec::new(),
+                    smoothing: Vec::new(),
+                    automation: Vec::new(),
                     ..old_voice
                 })
             }
@@ -723,3 +1759,164 @@ impl Rustaphone {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FilterMode;
+
+    #[test]
+    fn svf_modes_at_dc() {
+        let c = SvfCoefficients::new(1000.0, 0.707, 44100.0 * 8.0);
+        let settle = |mode| {
+            let mut svf = Svf::default();
+            (0..20000).fold(0.0, |_, _| svf.process(1.0, c, mode))
+        };
+        assert!((settle(FilterMode::LowPass) - 1.0).abs() < 1e-3);
+        assert!(settle(FilterMode::HighPass).abs() < 1e-3);
+        assert!(settle(FilterMode::BandPass).abs() < 1e-3);
+        assert!((settle(FilterMode::Notch) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn live_notes_cover_the_midi_range() {
+        for (pitch, hz) in [(0, 8.176), (69, 440.0), (127, 12543.85)] {
+            let mut live = Live::new(0.1);
+            let (slot, _) = live.note_on(&crate::Instrument::square(), pitch, 100, 0);
+            // 8 oversampled steps per sample at 44.1 kHz
+            let period = live.voices[slot].period;
+            let expected = 8.0 * 44100.0 / hz;
+            assert!((period / expected - 1.0).abs() < 1e-3, "{pitch}: {period}");
+            assert!((0..1000).any(|_| live.synth(44100) != 0.0), "{pitch}");
+        }
+    }
+
+    #[test]
+    fn smoothed_pitch_changes_keep_the_slide() {
+        let period = |slide| {
+            let mut voice = Voice {
+                params: Params {
+                    slide,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            voice.trigger(0.3);
+            voice.set_param(crate::params::param("freq").unwrap(), 0.4, 1000);
+            for _ in 0..500 {
+                voice.synth(44100);
+            }
+            voice.period
+        };
+        // an upward slide keeps shortening the period while it heads for the new pitch
+        assert!(period(0.5) < period(0.0) * 0.9);
+    }
+
+    #[test]
+    fn adsr_toggle_updates_the_envelope() {
+        let mut voice = Voice::default();
+        voice.update("sustain");
+        assert_ne!(voice.length[1], i32::MAX);
+        voice.set_param(crate::params::param("adsr").unwrap(), 1.0, 100);
+        assert_eq!(voice.length[2], i32::MAX);
+    }
+
+    #[test]
+    fn param_fx_clamp_to_the_param_range() {
+        let tune = notation::tune("[sweep -0.5][arp -2][volume 3][psweep + 0.4] C")
+            .unwrap()
+            .1;
+        let mut voice = Voice::default();
+        for fx in &tune.notes[0].fx {
+            voice.apply_fx(fx);
+        }
+        let params = &voice.params;
+        assert_eq!(
+            (params.sweep, params.arp, params.volume, params.psweep),
+            (-0.5, -1.0, 1.0, 0.4)
+        );
+    }
+
+    #[test]
+    fn tempo_directives_apply_once_for_all_tracks() {
+        let mut rustaphone = Rustaphone::new(120, 0.1);
+        for _ in 0..2 {
+            let tune = "[tempo +60] 2:C [time 3/4] C C C C";
+            rustaphone.add_track(Track::new(crate::Instrument::square(), tune));
+        }
+        rustaphone.play();
+        let mut sample = 0.0;
+        for _ in 0..44100 {
+            rustaphone.synth(44100, &mut sample);
+        }
+
+        // three beats at 180 bpm, the last of them in the first bar of 3/4
+        let clock = &rustaphone.clock;
+        assert_eq!(clock.bpm, 180.0);
+        assert_eq!((clock.meter, clock.bar), ((3, 4), 1));
+        let beat = (clock.ticks - clock.barstart) / TICKS_PER_BEAT as f64;
+        assert!((beat - 1.0).abs() < 0.01, "beat {beat}");
+    }
+
+    #[test]
+    fn automation_ramps_across_notes() {
+        let mut rustaphone = Rustaphone::new(120, 0.1);
+        let tune = "[lpf 0.2 -> 0.8 over 4] C D [cutoff 100 -> 400 over 2 exp] E F G";
+        rustaphone.add_track(Track::new(crate::Instrument::square(), tune));
+        rustaphone.play();
+        let mut sample = 0.0;
+        // a beat lasts half a second at 120 bpm
+        let mut beats = |beats: usize| {
+            for _ in 0..beats * 22050 {
+                rustaphone.synth(44100, &mut sample);
+            }
+            rustaphone.voices[0].as_ref().unwrap().params.clone()
+        };
+
+        let params = beats(1);
+        assert!((params.lpf - 0.35).abs() < 0.01);
+        assert_eq!(params.cutoff, 1000.0);
+        let params = beats(2);
+        assert!((params.lpf - 0.65).abs() < 0.01);
+        assert!((params.cutoff - 200.0).abs() < 2.0);
+        let params = beats(2);
+        assert_eq!((params.lpf, params.cutoff), (0.8, 400.0));
+    }
+
+    /// Renders the second beat of `tune` on a square wave, after a first beat of rest.
+    fn second_beat(tune: &str) -> Vec<f32> {
+        let mut rustaphone = Rustaphone::new(120, 0.1);
+        rustaphone.add_track(Track::new(crate::Instrument::square(), tune));
+        rustaphone.play();
+        let mut buffer = Vec::new();
+        for _ in 0..44100 {
+            let mut sample = 0.0;
+            rustaphone.synth(44100, &mut sample);
+            buffer.push(sample);
+        }
+        buffer.split_off(22050)
+    }
+
+    #[test]
+    fn fx_on_rests_reach_the_next_note() {
+        let peak = |buffer: &[f32]| buffer.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let step = |buffer: &[f32]| {
+            buffer
+                .windows(2)
+                .fold(0.0f32, |step, pair| step.max((pair[1] - pair[0]).abs()))
+        };
+        let plain = second_beat("4 C");
+        let quiet = second_beat("[volume 0.2] 4 C");
+        let filtered = second_beat("[lpf 0.2 -> 0.8 over 4] 4 C");
+        assert!(peak(&quiet) < peak(&plain) / 2.0);
+        assert!(step(&filtered) < step(&plain) / 2.0);
+    }
+
+    #[test]
+    fn standalone_fx_across_lines_reach_the_next_note() {
+        let peak = |buffer: &[f32]| buffer.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let plain = second_beat("// intro\n4\nC |");
+        let quiet = second_beat("// intro\n[volume 0.2] # quieter from here\n4\nC |");
+        assert!(peak(&quiet) < peak(&plain) / 2.0);
+    }
+}
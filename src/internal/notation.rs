@@ -1,13 +1,87 @@
+use std::{collections::HashMap, ops::Range};
+
 use nom::{
     branch::alt,
-    bytes::tag,
-    character::complete::{char, digit1, one_of, space0, space1},
-    combinator::{opt, verify},
+    bytes::complete::{tag, take_while1},
+    character::complete::{
+        alpha1, char, digit1, multispace1, not_line_ending, one_of, space0, space1,
+    },
+    combinator::{map_res, opt, verify},
     error::{Error, ErrorKind, ParseError},
+    multi::{many_m_n, separated_list1},
+    number::complete::float as beats,
     IResult, Parser,
 };
 
-use super::{Fx, FxCommand, Note};
+use super::{
+    super::{params, Warning},
+    transpose, Fx, FxCommand, Note, CHROMATIC, DEFAULT_VELOCITY, HI_OCTAVE, LO_OCTAVE,
+    TICKS_PER_WHOLE,
+};
+
+/// Highest count accepted in repeats, endings, tuplets and time signatures.
+const MAX_COUNT: usize = 99;
+/// Most notes a tune may expand to through repeats and phrases.
+const MAX_NOTES: usize = 1 << 16;
+
+/// Structural markers that are resolved by [`tune`] once they are parsed.
+#[derive(Clone)]
+enum Mark {
+    RepeatStart,
+    RepeatEnd(usize),
+    EndingStart(Vec<usize>),
+    EndingEnd,
+    PhraseStart(String),
+    PhraseEnd,
+    Phrase(String),
+    Loop,
+    Tie,
+    TupletStart(u32),
+    TupletEnd,
+    Bar,
+    /// Velocity change spread over the notes up to the matching `DynamicEnd`.
+    DynamicStart(i32),
+    DynamicEnd,
+}
+
+/// An open repeat block or phrase definition, with the index of its first note.
+enum Block {
+    Repeat {
+        start: usize,
+        endings: Vec<(Vec<usize>, Range<usize>)>,
+        ending: Option<(Vec<usize>, usize)>,
+    },
+    Phrase {
+        name: String,
+        start: usize,
+    },
+    Tuplet {
+        count: u32,
+        start: usize,
+    },
+    Dynamic {
+        change: i32,
+        start: usize,
+    },
+}
+
+impl Block {
+    fn name(&self) -> &'static str {
+        match self {
+            Block::Repeat { .. } => "repeat",
+            Block::Phrase { .. } => "phrase definition",
+            Block::Tuplet { .. } => "tuplet",
+            Block::Dynamic { .. } => "crescendo or diminuendo",
+        }
+    }
+}
+
+pub struct Tune {
+    pub notes: Vec<Note>,
+    /// Index of the note to continue from once the end is reached.
+    pub loop_start: Option<usize>,
+    pub warnings: Vec<Warning>,
+}
 
 #[derive(Clone)]
 struct ParseState {
@@ -15,11 +89,20 @@ struct ParseState {
     fxmod: Option<char>,
     fxval: f32,
     len: i32,
+    dots: u32,
     oct: i32,
     modifier: Option<char>,
     fx: Vec<Fx>,
     tone: char,
+    pitches: Vec<(char, u8)>,
     note: Option<Note>,
+    mark: Option<Mark>,
+    /// Key signature as a number of sharps, or flats when negative.
+    key: i32,
+    transpose: i32,
+    velocity: u8,
+    /// The next note is slurred to the previous one.
+    slur: bool,
 }
 
 #[derive(Clone)]
@@ -38,7 +121,7 @@ impl<'a> ParseError<StatefulInput<'a>> for Error<&'a str> {
     }
 }
 
-fn dec_frac(input: StatefulInput) -> IResult<StatefulInput, f32, Error<&str>> {
+fn dec_frac(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, f32, Error<&str>> {
     let StatefulInput { input, state } = input;
     let (input, _) = char('.').parse(input)?;
     let (input, fractional_part) = digit1(input)?;
@@ -49,7 +132,7 @@ fn dec_frac(input: StatefulInput) -> IResult<StatefulInput, f32, Error<&str>> {
     Ok((StatefulInput { input, state }, result))
 }
 
-fn dec(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn dec(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
     let (input, integer_part) = digit1(input)?;
     state.fxval = integer_part.parse::<f32>().unwrap();
@@ -60,7 +143,7 @@ fn dec(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
     Ok((input, ()))
 }
 
-fn float(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn float(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, state } = input;
     let (input, neg) = opt(char('-')).parse(input)?;
     let (mut input, _) = dec.parse(StatefulInput { input, state })?;
@@ -71,418 +154,1270 @@ fn float(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
     Ok((input, ()))
 }
 
-fn fxcmd_volume(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn fxcmd_duty(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("volume").parse(input)?;
-    state.fxcmd = Some(FxCommand::Volume);
+    let (input, _) = tag("duty").parse(input)?;
+    state.fxcmd = Some(FxCommand::Duty);
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_punch(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn fxcmd_tempo(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("punch").parse(input)?;
-    state.fxcmd = Some(FxCommand::Punch);
+    // accel and rit read better for gradual changes, but all three do the same
+    let (input, _) = alt((tag("tempo"), tag("accel"), tag("rit"))).parse(input)?;
+    state.fxcmd = Some(FxCommand::Tempo);
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_attack(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn fxcmd_param(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("attack").parse(input)?;
-    state.fxcmd = Some(FxCommand::Attack);
+    let (rest, name) = alpha1.parse(input)?;
+    let Some(param) = params::param(name) else {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::Tag)));
+    };
+    state.fxcmd = Some(FxCommand::Param(param));
 
-    Ok((StatefulInput { input, state }, ()))
+    Ok((StatefulInput { input: rest, state }, ()))
+}
+
+fn fxcmd(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    alt((fxcmd_duty, fxcmd_tempo, fxcmd_param)).parse(input)
 }
 
-fn fxcmd_sustain(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn len(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("sustain").parse(input)?;
-    state.fxcmd = Some(FxCommand::Sustain);
+    let digits = verify(digit1, |len: &str| !len.starts_with('0'));
+    let (input, len) = map_res(digits, str::parse::<i32>).parse(input)?;
+    let (input, dots) = many_m_n(0, 2, char('.')).parse(input)?;
+    let (input, _) = opt(char(':')).parse(input)?;
+
+    state.len = len;
+    state.dots = dots.len() as u32;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_decay(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn up(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("decay").parse(input)?;
-    state.fxcmd = Some(FxCommand::Decay);
+    let (input, _) = char('+').parse(input)?;
+    state.len = 1;
+    let (input, _) = opt(len).parse(StatefulInput { input, state })?;
 
-    Ok((StatefulInput { input, state }, ()))
+    Ok((input, ()))
 }
 
-fn fxcmd_square(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn down(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("square").parse(input)?;
-    state.fxcmd = Some(FxCommand::Square);
+    let (input, _) = char('-').parse(input)?;
+    state.len = 1;
+    let (input, _) = opt(len).parse(StatefulInput { input, state })?;
 
-    Ok((StatefulInput { input, state }, ()))
+    Ok((input, ()))
 }
 
-fn fxcmd_sweep(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn modifier(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("sweep").parse(input)?;
-    state.fxcmd = Some(FxCommand::Sweep);
+    // `=` is a natural sign, cancelling the key signature
+    let (input, modifier) = alt((char('b'), char('#'), char('='))).parse(input)?;
+    state.modifier = Some(modifier);
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_vibe(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn oct(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("vibe").parse(input)?;
-    state.fxcmd = Some(FxCommand::Vibe);
+    let (input, oct) = one_of("12345678").parse(input)?;
+
+    state.oct = oct.to_digit(10).unwrap() as i32;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_vspeed(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn fxmod(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("vspeed").parse(input)?;
-    state.fxcmd = Some(FxCommand::VSpeed);
+    let (input, fxmod) = alt((char('+'), char('-'))).parse(input)?;
+    state.fxmod = Some(fxmod);
+    // `-` needs a separator to tell it apart from a negative value, `+` does not
+    let (input, separator) = opt(alt((tag(":"), space1))).parse(input)?;
+    if fxmod == '-' && separator.is_none() {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::Char)));
+    }
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_vdelay(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn fx_param(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, state } = input;
+    let (input, _) = char('[').parse(input)?;
+    let (input, _) = fxcmd.parse(StatefulInput { input, state })?;
+    let StatefulInput { input, state } = input;
+    let (input, _) = alt((tag(":"), space0)).parse(input)?;
+    let (input, _) = opt(fxmod).parse(StatefulInput { input, state })?;
+    let (input, _) = float.parse(input)?;
+    // `[lpf 0.2 -> 0.8 over 4]` ramps from a value of its own rather than the current one
+    let StatefulInput { input, state } = input;
+    let (input, arrow) = opt((space0, tag("->"), space0)).parse(input)?;
+    let mut input = StatefulInput { input, state };
+    let mut from = None;
+    if arrow.is_some() {
+        from = Some(input.state.fxval);
+        (input, _) = float.parse(input)?;
+    }
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("vdelay").parse(input)?;
-    state.fxcmd = Some(FxCommand::VDelay);
+    let (input, over) = opt((
+        space1,
+        tag("over"),
+        space1,
+        verify(beats, |beats: &f32| beats.is_finite() && *beats > 0.0),
+        opt((space1, tag("exp"))),
+    ))
+    .parse(input)?;
+    let (input, _) = char(']').parse(input)?;
+
+    let fx = Fx {
+        command: state.fxcmd.unwrap(),
+        val: state.fxval,
+        r#mod: state.fxmod.unwrap_or('\0'),
+        over: over.map(|(_, _, _, beats, _)| beats),
+        from,
+        exponential: over.is_some_and(|(.., exp)| exp.is_some()),
+    };
+    state.fx.push(fx);
+    state.fxcmd = None;
+    state.fxval = 0.0;
+    state.fxmod = None;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_lpf(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn fx_time(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("lpf").parse(input)?;
-    state.fxcmd = Some(FxCommand::Lpf);
+    let (input, _) = (char('['), tag("time"), space1).parse(input)?;
+    let (input, (beats, _, unit)) = (count, char('/'), count).parse(input)?;
+    let (input, _) = char(']').parse(input)?;
+
+    state.fx.push(Fx {
+        command: FxCommand::Time(beats.min(255) as u8, unit.min(255) as u8),
+        val: 0.0,
+        r#mod: '\0',
+        over: None,
+        from: None,
+        exponential: false,
+    });
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_lsweep(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn fx_inst(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("lsweep").parse(input)?;
-    state.fxcmd = Some(FxCommand::LSweep);
+    let (input, _) = (char('['), tag("inst"), space1).parse(input)?;
+    let (input, name) =
+        take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-').parse(input)?;
+    let (input, _) = char(']').parse(input)?;
+
+    state.fx.push(Fx {
+        command: FxCommand::Instrument(name.to_string()),
+        val: 0.0,
+        r#mod: '\0',
+        over: None,
+        from: None,
+        exponential: false,
+    });
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_resonance(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn fx(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    alt((fx_time, fx_inst, fx_param)).parse(input)
+}
+
+fn pitch(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("resonance").parse(input)?;
-    state.fxcmd = Some(FxCommand::Resonance);
+    let (input, tone) = one_of("abcdefgABCDEFG").parse(input)?;
+    state.tone = tone;
+    let (input, _) = opt(modifier).parse(StatefulInput { input, state })?;
+    let (mut input, _) = opt(oct).parse(input)?;
 
-    Ok((StatefulInput { input, state }, ()))
+    let state = &input.state;
+    let pitch = resolve_pitch(state.tone, state.modifier, state.key, octave(state.oct));
+    let (tone, octave) = transpose(pitch.0, pitch.1, state.transpose);
+    input.state.pitches.push((tone, octave));
+    input.state.modifier = None;
+    input.state.tone = '\0';
+
+    Ok((input, ()))
 }
 
-fn fxcmd_hpf(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("hpf").parse(input)?;
-    state.fxcmd = Some(FxCommand::Hpf);
+fn chord_slash(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, state } = input;
+    let (input, _) = char('/').parse(input)?;
+    pitch.parse(StatefulInput { input, state })
+}
+
+fn chord_space(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, state } = input;
+    let (input, _) = space1.parse(input)?;
+    pitch.parse(StatefulInput { input, state })
+}
+
+fn chord_paren(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, state } = input;
+    let (input, _) = char('(').parse(input)?;
+    let (input, _) = space0.parse(input)?;
+    let (mut input, _) = pitch.parse(StatefulInput { input, state })?;
+    let mut more;
+    (input, more) = opt(chord_space).parse(input)?;
+    while more.is_some() {
+        (input, more) = opt(chord_space).parse(input)?;
+    }
+    let StatefulInput { input, state } = input;
+    let (input, _) = space0.parse(input)?;
+    let (input, _) = char(')').parse(input)?;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_hsweep(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn velocity(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("hsweep").parse(input)?;
-    state.fxcmd = Some(FxCommand::HSweep);
+    let (input, accent) = one_of("!?@").parse(input)?;
+    let (input, velocity) = match accent {
+        '!' => (input, 120),
+        '?' => (input, 50),
+        _ => {
+            let (input, velocity) = digit1.parse(input)?;
+            (
+                input,
+                velocity.parse::<u32>().unwrap_or(u32::MAX).min(127) as u8,
+            )
+        }
+    };
+    state.velocity = velocity;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_arp(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("arp").parse(input)?;
-    state.fxcmd = Some(FxCommand::Arp);
+fn note(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let (input, _) = opt(len).parse(input)?;
+    let (mut input, _) = alt((chord_paren, pitch)).parse(input)?;
+    let mut slash;
+    (input, slash) = opt(chord_slash).parse(input)?;
+    while slash.is_some() {
+        (input, slash) = opt(chord_slash).parse(input)?;
+    }
+    let (input, _) = opt(velocity).parse(input)?;
+
+    let (mut input, mut effect) = opt(fx).parse(input)?;
+    while effect.is_some() {
+        (input, effect) = opt(fx).parse(input)?;
+    }
+
+    Ok((input, ()))
+}
+
+/// Converts a note length (4 is a quarter note) with `dots` augmentation dots into ticks.
+fn ticks(len: i32, dots: u32) -> u32 {
+    let num = TICKS_PER_WHOLE as u64 * ((2 << dots) - 1);
+    let den = len as u64 * (1 << dots);
+    ((num + den / 2) / den).max(1) as u32
+}
+
+/// Whether a tie can join `a` and `b` into one longer note.
+fn same_pitches(a: &Note, b: &Note) -> bool {
+    (a.tone, a.octave, &a.chord) == (b.tone, b.octave, &b.chord)
+}
+
+fn tune_len(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let (mut input, _) = len.parse(input)?;
+
+    input.state.note = Some(Note {
+        tone: '\0',
+        octave: octave(input.state.oct),
+        duration: ticks(input.state.len, input.state.dots),
+        fx: std::mem::take(&mut input.state.fx),
+        chord: Vec::new(),
+        velocity: 0,
+        legato: false,
+    });
+    input.state.slur = false;
+    input.state.modifier = None;
+    input.state.tone = '\0';
+    input.state.len = 4;
+    input.state.dots = 0;
+    input.state.fxmod = None;
+    input.state.fxval = 0.0;
+
+    Ok((input, ()))
+}
+
+/// Keeps an octave from `+` and `-` within the playable range.
+fn octave(oct: i32) -> u8 {
+    oct.clamp(LO_OCTAVE as i32, HI_OCTAVE as i32) as u8
+}
+
+/// Sharps come in this order, flats in the reverse one.
+const SHARPS: [char; 7] = ['F', 'C', 'G', 'D', 'A', 'E', 'B'];
+
+/// Turns a written tone into a chromatic tone letter, taking the key signature into account when
+/// no accidental is written.
+fn resolve_pitch(tone: char, modifier: Option<char>, key: i32, octave: u8) -> (char, u8) {
+    let tone = tone.to_ascii_uppercase();
+    let natural = match tone {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return ('\0', octave),
+    };
+    let accidental = match modifier {
+        Some('#') => 1,
+        Some('b') => -1,
+        Some(_) => 0,
+        None => key_accidental(key, tone),
+    };
+
+    // B# and Cb cross into the neighbouring octave
+    transpose(CHROMATIC[natural], octave, accidental)
+}
+
+fn key_accidental(key: i32, tone: char) -> i32 {
+    let position = SHARPS.iter().position(|&t| t == tone).unwrap() as i32;
+    if position < key {
+        1
+    } else if 6 - position < -key {
+        -1
+    } else {
+        0
+    }
+}
+
+/// Number of sharps (or flats, when negative) in a key such as `D`, `Bb` or `F#m`.
+fn key_signature(tonic: char, modifier: Option<char>, minor: bool) -> Option<i32> {
+    let fifths = SHARPS
+        .iter()
+        .position(|&t| t == tonic.to_ascii_uppercase())? as i32
+        - 1;
+    let fifths = fifths
+        + match modifier {
+            Some('#') => 7,
+            Some('b') => -7,
+            _ => 0,
+        }
+        // a minor key shares its signature with the major key a minor third up
+        - if minor { 3 } else { 0 };
+
+    (-7..=7).contains(&fifths).then_some(fifths)
+}
+
+fn tune_note(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let (mut input, _) = note.parse(input)?;
+
+    let mut pitches = std::mem::take(&mut input.state.pitches).into_iter();
+    let (tone, octave) = pitches.next().unwrap();
+    input.state.note = Some(Note {
+        tone,
+        octave,
+        duration: ticks(input.state.len, input.state.dots),
+        fx: input.state.fx.clone(),
+        chord: pitches.collect(),
+        velocity: std::mem::replace(&mut input.state.velocity, DEFAULT_VELOCITY),
+        legato: std::mem::take(&mut input.state.slur),
+    });
+    input.state.modifier = None;
+    input.state.tone = '\0';
+    input.state.len = 4;
+    input.state.dots = 0;
+    input.state.fxmod = None;
+    input.state.fxval = 0.0;
+    input.state.fx = Vec::new();
+
+    Ok((input, ()))
+}
+
+fn tune_up(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let (mut input, _) = up.parse(input)?;
+
+    input.state.oct += 1;
+    input.state.len = 4;
+    input.state.dots = 0;
+
+    Ok((input, ()))
+}
+
+fn tune_down(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let (mut input, _) = down.parse(input)?;
+
+    input.state.oct -= 1;
+    input.state.len = 4;
+    input.state.dots = 0;
+
+    Ok((input, ()))
+}
+
+fn tune_space(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, state } = input;
+    let (input, _) = multispace1.parse(input)?;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_aspeed(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("aspeed").parse(input)?;
-    state.fxcmd = Some(FxCommand::ASpeed);
+fn tune_comment(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, state } = input;
+    // only at the start of a token, so `C#` is still a sharp
+    let (input, _) = (alt((tag("#"), tag("//"))), not_line_ending).parse(input)?;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_phase(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn tune_bar(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("phase").parse(input)?;
-    state.fxcmd = Some(FxCommand::Phase);
+    let (input, _) = char('|').parse(input)?;
+    state.mark = Some(Mark::Bar);
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_psweep(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn count(input: &str) -> IResult<&str, usize, Error<&str>> {
+    let digits = verify(digit1, |count: &str| !count.starts_with('0'));
+    verify(map_res(digits, str::parse::<usize>), |&count| {
+        count <= MAX_COUNT
+    })
+    .parse(input)
+}
+
+fn name(input: &str) -> IResult<&str, &str, Error<&str>> {
+    let (input, _) = char('$').parse(input)?;
+    take_while1(|c: char| c.is_alphanumeric() || c == '_').parse(input)
+}
+
+fn tune_repeat_start(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("psweep").parse(input)?;
-    state.fxcmd = Some(FxCommand::PSweep);
+    let (input, _) = tag("|:").parse(input)?;
+    state.mark = Some(Mark::RepeatStart);
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd_repeat(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn tune_repeat_end(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = tag("repeat").parse(input)?;
-    state.fxcmd = Some(FxCommand::Repeat);
+    let (input, _) = tag(":|").parse(input)?;
+    let (input, times) = opt((char('x'), count)).parse(input)?;
+    state.mark = Some(Mark::RepeatEnd(times.map_or(2, |(_, times)| times)));
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxcmd(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    alt((
-        fxcmd_volume,
-        fxcmd_punch,
-        fxcmd_attack,
-        fxcmd_sustain,
-        fxcmd_decay,
-        fxcmd_square,
-        fxcmd_sweep,
-        fxcmd_vibe,
-        fxcmd_vspeed,
-        fxcmd_vdelay,
-        fxcmd_lpf,
-        fxcmd_lsweep,
-        fxcmd_resonance,
-        fxcmd_hpf,
-        fxcmd_hsweep,
-        fxcmd_arp,
-        fxcmd_aspeed,
-        fxcmd_phase,
-        fxcmd_psweep,
-        fxcmd_repeat,
-    ))
-    .parse(input)
+fn tune_ending_start(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, mut state } = input;
+    let (input, _) = char('[').parse(input)?;
+    let (input, passes) = separated_list1(char(','), count).parse(input)?;
+    let (input, _) = space1.parse(input)?;
+    state.mark = Some(Mark::EndingStart(passes));
+
+    Ok((StatefulInput { input, state }, ()))
 }
 
-fn len(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn tune_ending_end(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, len) = verify(digit1, |len: &str| !len.starts_with('0')).parse(input)?;
-    let (input, _) = opt(char(':')).parse(input)?;
-
-    state.len = len.parse::<i32>().unwrap();
+    let (input, _) = char(']').parse(input)?;
+    state.mark = Some(Mark::EndingEnd);
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn up(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn tune_phrase_start(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = char('+').parse(input)?;
-    state.len = 1;
-    let (input, _) = opt(len).parse(StatefulInput { input, state })?;
+    let (input, name) = name.parse(input)?;
+    let (input, _) = (space0, char('=')).parse(input)?;
+    state.mark = Some(Mark::PhraseStart(name.to_string()));
 
-    Ok((input, ()))
+    Ok((StatefulInput { input, state }, ()))
 }
 
-fn down(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn tune_phrase_end(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, _) = char('-').parse(input)?;
-    state.len = 1;
-    let (input, _) = opt(len).parse(StatefulInput { input, state })?;
+    let (input, _) = char(';').parse(input)?;
+    state.mark = Some(Mark::PhraseEnd);
 
-    Ok((input, ()))
+    Ok((StatefulInput { input, state }, ()))
 }
 
-fn modifier(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn tune_phrase(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, modifier) = alt((char('b'), char('#'))).parse(input)?;
-    state.modifier = Some(modifier);
+    let (input, name) = name.parse(input)?;
+    state.mark = Some(Mark::Phrase(name.to_string()));
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn oct(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn tune_loop(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, oct) = one_of("12345678").parse(input)?;
-
-    state.oct = oct.to_digit(10).unwrap() as i32;
+    let (input, _) = tag("[loop]").parse(input)?;
+    state.mark = Some(Mark::Loop);
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fxmod(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
+fn tune_fx(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    // effects in front of a note are collected and attached to it
+    fx.parse(input)
+}
+
+fn tune_key(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, fxmod) = alt((char('+'), char('-'))).parse(input)?;
-    state.fxmod = Some(fxmod);
-    let (input, _) = alt((tag(":"), space1)).parse(input)?;
+    let (input, _) = (char('['), tag("key"), space1).parse(input)?;
+    let (input, tonic) = one_of("abcdefgABCDEFG").parse(input)?;
+    let (input, modifier) = opt(one_of("#b")).parse(input)?;
+    let (input, minor) = opt(char('m')).parse(input)?;
+    let (input, _) = char(']').parse(input)?;
+
+    let Some(key) = key_signature(tonic, modifier, minor.is_some()) else {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::Verify)));
+    };
+    state.key = key;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn fx(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    let StatefulInput { input, state } = input;
-    let (input, _) = char('[').parse(input)?;
-    let (input, _) = fxcmd.parse(StatefulInput { input, state })?;
-    let StatefulInput { input, state } = input;
-    let (input, _) = alt((tag(":"), space0)).parse(input)?;
-    let (input, _) = opt(fxmod).parse(StatefulInput { input, state })?;
-    let (input, _) = float.parse(input)?;
+fn tune_transpose(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
+    let (input, _) = (char('['), tag("transpose"), space1).parse(input)?;
+    let (input, sign) = opt(one_of("+-")).parse(input)?;
+    let (input, semitones) = digit1.parse(input)?;
     let (input, _) = char(']').parse(input)?;
 
-    let fx = Fx {
-        command: state.fxcmd.unwrap(),
-        val: state.fxval,
-        r#mod: state.fxmod.unwrap_or('\0'),
+    // too many digits for an i32 is still past the top of the range
+    let semitones = semitones
+        .parse::<i32>()
+        .map_or(127, |semitones| semitones.min(127));
+    state.transpose = if sign == Some('-') {
+        -semitones
+    } else {
+        semitones
     };
-    state.fx.push(fx);
-    state.fxcmd = None;
-    state.fxval = 0.0;
-    state.fxmod = None;
 
     Ok((StatefulInput { input, state }, ()))
 }
 
-fn note(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    let (input, _) = opt(len).parse(input)?;
+fn tune_dynamic_start(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
     let StatefulInput { input, mut state } = input;
-    let (input, tone) = one_of("abcdefgABCDEFG").parse(input)?;
-    state.tone = tone;
-    let (input, _) = opt(modifier).parse(StatefulInput { input, state })?;
-    let (input, _) = opt(oct).parse(input)?;
+    let (input, (_, kind, _, change, _)) = (
+        char('['),
+        alt((tag("cresc"), tag("dim"))),
+        space1,
+        digit1,
+        char(']'),
+    )
+        .parse(input)?;
+
+    let change = change.parse::<i32>().unwrap_or(127).min(127);
+    state.mark = Some(Mark::DynamicStart(if kind == "dim" {
+        -change
+    } else {
+        change
+    }));
 
-    let (mut input, mut effect) = opt(fx).parse(input)?;
-    while effect.is_some() {
-        (input, effect) = opt(fx).parse(input)?;
-    }
+    Ok((StatefulInput { input, state }, ()))
+}
 
-    Ok((input, ()))
+fn tune_dynamic_end(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, mut state } = input;
+    let (input, _) = alt((tag("[/cresc]"), tag("[/dim]"))).parse(input)?;
+    state.mark = Some(Mark::DynamicEnd);
+
+    Ok((StatefulInput { input, state }, ()))
 }
 
-fn tune_len(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    println!("tune_len");
-    let (mut input, _) = len.parse(input)?;
+/// Bracketed directives that aren't structural.
+fn tune_directive(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    alt((
+        tune_loop,
+        tune_key,
+        tune_transpose,
+        tune_dynamic_start,
+        tune_dynamic_end,
+        tune_fx,
+    ))
+    .parse(input)
+}
 
-    input.state.note = Some(Note {
-        tone: '\0',
-        octave: input.state.oct as u8,
-        duration: input.state.len as u8,
-        fx: input.state.fx.clone(),
-    });
-    input.state.modifier = None;
-    input.state.tone = '\0';
-    input.state.len = 4;
-    input.state.fxmod = None;
-    input.state.fxval = 0.0;
+fn tune_slur(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, mut state } = input;
+    let (input, _) = char('_').parse(input)?;
+    state.slur = true;
 
-    Ok((input, ()))
+    Ok((StatefulInput { input, state }, ()))
 }
 
-fn tune_note(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    println!("tune_note");
-    let (mut input, _) = note.parse(input)?;
+fn tune_tie(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, mut state } = input;
+    let (input, _) = char('~').parse(input)?;
+    state.mark = Some(Mark::Tie);
 
-    let tone = match input.state.tone {
-        'a' | 'A' => match input.state.modifier {
-            Some('b') => 'a',
-            Some('#') => 'b',
-            _ => 'A',
-        },
-        'b' | 'B' => match input.state.modifier {
-            Some('b') => 'b',
-            Some('#') => 'C',
-            _ => 'B',
-        },
-        'c' | 'C' => match input.state.modifier {
-            Some('b') => 'B',
-            Some('#') => 'd',
-            _ => 'C',
-        },
-        'd' | 'D' => match input.state.modifier {
-            Some('b') => 'd',
-            Some('#') => 'e',
-            _ => 'D',
-        },
-        'e' | 'E' => match input.state.modifier {
-            Some('b') => 'e',
-            Some('#') => 'f',
-            _ => 'E',
-        },
-        'f' | 'F' => match input.state.modifier {
-            Some('b') => 'E',
-            Some('#') => 'g',
-            _ => 'F',
-        },
-        'g' | 'G' => match input.state.modifier {
-            Some('b') => 'g',
-            Some('#') => 'a',
-            _ => 'G',
-        },
-        _ => '\0',
-    };
+    Ok((StatefulInput { input, state }, ()))
+}
 
-    input.state.note = Some(Note {
-        tone,
-        octave: input.state.oct as u8,
-        duration: input.state.len as u8,
-        fx: input.state.fx.clone(),
-    });
-    input.state.modifier = None;
-    input.state.tone = '\0';
-    input.state.len = 4;
-    input.state.fxmod = None;
-    input.state.fxval = 0.0;
-    input.state.fx = Vec::new();
+fn tune_tuplet_start(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, mut state } = input;
+    let (input, count) = verify(count, |&count| count > 1).parse(input)?;
+    let (input, _) = char('{').parse(input)?;
+    state.mark = Some(Mark::TupletStart(count as u32));
 
-    Ok((input, ()))
+    Ok((StatefulInput { input, state }, ()))
 }
 
-fn tune_up(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    println!("tune_up");
-    let (mut input, _) = up.parse(input)?;
+fn tune_tuplet_end(input: StatefulInput<'_>) -> IResult<StatefulInput<'_>, (), Error<&str>> {
+    let StatefulInput { input, mut state } = input;
+    let (input, _) = char('}').parse(input)?;
+    state.mark = Some(Mark::TupletEnd);
 
-    input.state.oct += 1;
-    input.state.len = 4;
+    Ok((StatefulInput { input, state }, ()))
+}
 
-    Ok((input, ()))
+/// Squeezes `notes` into the time of the largest power of two below `count` (three for
+/// duplets), distributing rounding so the total stays exact.
+fn scale_tuplet(notes: &mut [Note], count: u32) {
+    let time = if count == 2 {
+        3
+    } else {
+        1 << (31 - (count - 1).leading_zeros())
+    };
+
+    let mut total = 0;
+    let mut scaled = 0;
+    for note in notes {
+        total += note.duration as u64;
+        let end = (total * time + count as u64 / 2) / count as u64;
+        note.duration = (end - scaled).max(1) as u32;
+        scaled = end;
+    }
 }
 
-fn tune_down(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    println!("tune_down");
-    let (mut input, _) = down.parse(input)?;
+/// Ramps the velocity of `notes` towards `change`, reaching it on the last one.
+fn scale_dynamic(notes: &mut [Note], change: i32) {
+    let steps = notes.len().saturating_sub(1) as i32;
+    for (i, note) in notes.iter_mut().enumerate() {
+        // rests stay silent
+        if note.tone == '\0' {
+            continue;
+        }
+        let offset = if steps == 0 {
+            change
+        } else {
+            change * i as i32 / steps
+        };
+        note.velocity = (note.velocity as i32 + offset).clamp(1, 127) as u8;
+    }
+}
 
-    input.state.oct += 1;
-    input.state.len = 4;
+/// Plays `body` once per pass, picking the endings that belong to each pass.
+fn expand_repeat(
+    body: Vec<Note>,
+    mut endings: Vec<(Vec<usize>, Range<usize>)>,
+    times: usize,
+) -> Vec<Note> {
+    endings.sort_by_key(|(_, range)| range.start);
+
+    let mut notes = Vec::new();
+    for pass in 1..=times {
+        let mut at = 0;
+        for (passes, range) in &endings {
+            notes.extend_from_slice(&body[at..range.start]);
+            if passes.contains(&pass) {
+                notes.extend_from_slice(&body[range.clone()]);
+            }
+            at = range.end;
+        }
+        notes.extend_from_slice(&body[at..]);
+    }
 
-    Ok((input, ()))
+    notes
 }
 
-fn tune_space(input: StatefulInput) -> IResult<StatefulInput, (), Error<&str>> {
-    println!("tune_space");
-    let StatefulInput { input, state } = input;
-    let (input, _) = space1.parse(input)?;
+/// Keeps track of where the current bar started, to check its length at the next bar line.
+struct Bars {
+    start: usize,
+    /// Ticks tied over from the previous bar.
+    carry: u64,
+    meter: (u8, u8),
+    number: usize,
+}
 
-    Ok((StatefulInput { input, state }, ()))
+impl Bars {
+    fn check(&mut self, notes: &[Note], warnings: &mut Vec<Warning>) {
+        let bar = &notes[self.start.min(notes.len())..];
+        for fx in bar.iter().flat_map(|note| &note.fx) {
+            if let FxCommand::Time(beats, unit) = fx.command {
+                self.meter = (beats, unit);
+            }
+        }
+        self.start = notes.len();
+        let carry = std::mem::take(&mut self.carry);
+        if bar.is_empty() {
+            return;
+        }
+        self.number += 1;
+
+        let (beats, unit) = self.meter;
+        let ticks = bar.iter().map(|note| note.duration as u64).sum::<u64>() + carry;
+        let expected = TICKS_PER_WHOLE as u64 * beats as u64 / unit.max(1) as u64;
+        // the first bar may be a pickup, and rounded tuplets and dots may be a tick off each
+        let pickup = self.number == 1 && ticks < expected;
+        if !pickup && ticks.abs_diff(expected) > bar.len() as u64 {
+            warnings.push(Warning::BarLength {
+                bar: self.number,
+                beats: (ticks * unit as u64) as f32 / TICKS_PER_WHOLE as f32,
+                expected: beats as f32,
+            });
+        }
+    }
 }
 
-pub fn tune(input: &str) -> IResult<&str, Vec<Note>, Error<&str>> {
+/// Resolves a structural mark against the notes parsed so far. Returns why the mark does not
+/// fit, e.g. a repeat end without a matching start.
+fn apply_mark(
+    mark: Mark,
+    tune: &mut Tune,
+    blocks: &mut Vec<Block>,
+    phrases: &mut HashMap<String, Vec<Note>>,
+) -> Result<(), &'static str> {
+    let notes = &mut tune.notes;
+    match mark {
+        Mark::RepeatStart => blocks.push(Block::Repeat {
+            start: notes.len(),
+            endings: Vec::new(),
+            ending: None,
+        }),
+        Mark::RepeatEnd(times) => {
+            let Some(Block::Repeat {
+                start,
+                endings,
+                ending: None,
+            }) = blocks.pop()
+            else {
+                return Err("repeat end without a start");
+            };
+            if start + (notes.len() - start) * times > MAX_NOTES {
+                return Err("too many notes");
+            }
+            let endings = endings
+                .into_iter()
+                .map(|(passes, range)| (passes, range.start - start..range.end - start))
+                .collect();
+            let body = notes.split_off(start);
+            notes.extend(expand_repeat(body, endings, times));
+        }
+        Mark::EndingStart(passes) => {
+            let Some(Block::Repeat { ending, .. }) = blocks.last_mut() else {
+                return Err("ending outside a repeat");
+            };
+            if ending.is_some() {
+                return Err("ending inside an ending");
+            }
+            *ending = Some((passes, notes.len()));
+        }
+        Mark::EndingEnd => {
+            let Some(Block::Repeat {
+                endings, ending, ..
+            }) = blocks.last_mut()
+            else {
+                return Err("ending end without a start");
+            };
+            let Some((passes, start)) = ending.take() else {
+                return Err("ending end without a start");
+            };
+            endings.push((passes, start..notes.len()));
+        }
+        Mark::PhraseStart(name) => blocks.push(Block::Phrase {
+            name,
+            start: notes.len(),
+        }),
+        Mark::PhraseEnd => {
+            let Some(Block::Phrase { name, start }) = blocks.pop() else {
+                return Err("phrase end without a start");
+            };
+            phrases.insert(name, notes.split_off(start));
+        }
+        Mark::Phrase(name) => {
+            let Some(phrase) = phrases.get(&name) else {
+                return Err("unknown phrase");
+            };
+            if notes.len() + phrase.len() > MAX_NOTES {
+                return Err("too many notes");
+            }
+            notes.extend_from_slice(phrase);
+        }
+        Mark::Loop => tune.loop_start = Some(notes.len()),
+        Mark::Tie => unreachable!("ties are resolved as notes are added"),
+        Mark::Bar => {}
+        Mark::DynamicStart(change) => blocks.push(Block::Dynamic {
+            change,
+            start: notes.len(),
+        }),
+        Mark::DynamicEnd => {
+            let Some(Block::Dynamic { change, start }) = blocks.pop() else {
+                return Err("dynamic end without a start");
+            };
+            scale_dynamic(&mut notes[start..], change);
+        }
+        Mark::TupletStart(count) => blocks.push(Block::Tuplet {
+            count,
+            start: notes.len(),
+        }),
+        Mark::TupletEnd => {
+            let Some(Block::Tuplet { count, start }) = blocks.pop() else {
+                return Err("tuplet end without a start");
+            };
+            scale_tuplet(&mut notes[start..], count);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn tune(input: &str) -> IResult<&str, Tune, Error<&str>> {
     let state = ParseState {
         fxcmd: None,
         fxmod: None,
         fxval: 0.0,
         len: 4,
+        dots: 0,
         oct: 4,
         modifier: None,
         fx: Vec::new(),
         tone: '\0',
+        pitches: Vec::new(),
         note: None,
+        mark: None,
+        key: 0,
+        transpose: 0,
+        velocity: DEFAULT_VELOCITY,
+        slur: false,
     };
     let mut input = StatefulInput { input, state };
-    let mut tune = Vec::new();
+    let mut tune = Tune {
+        notes: Vec::new(),
+        loop_start: None,
+        warnings: Vec::new(),
+    };
+    let mut blocks = Vec::new();
+    let mut phrases = HashMap::new();
+    let mut tie = false;
+    let mut invalid = None;
+    let mut bars = Bars {
+        start: 0,
+        carry: 0,
+        meter: (4, 4),
+        number: 0,
+    };
 
-    while let Ok((mut rest, _)) =
-        alt((tune_note, tune_len, tune_up, tune_down, tune_space)).parse(input.clone())
+    while let Ok((mut rest, _)) = alt((
+        tune_note,
+        tune_tuplet_start,
+        tune_len,
+        tune_up,
+        tune_down,
+        tune_space,
+        tune_comment,
+        tune_repeat_start,
+        tune_repeat_end,
+        tune_directive,
+        tune_ending_start,
+        tune_ending_end,
+        tune_phrase_start,
+        tune_phrase_end,
+        tune_phrase,
+        tune_tie,
+        tune_tuplet_end,
+        tune_bar,
+        tune_slur,
+    ))
+    .parse(input.clone())
     {
-        println!("rest: {:?}", rest.input);
         if let Some(note) = rest.state.note.take() {
-            dbg!(&note);
-            tune.push(note);
+            match tune.notes.last_mut() {
+                Some(last) if tie && !same_pitches(last, &note) => {
+                    tune.warnings.push(Warning::MismatchedTie {
+                        bar: bars.number + 1,
+                    });
+                    tune.notes.push(note);
+                }
+                Some(last) if tie => {
+                    last.duration += note.duration;
+                    last.fx.extend(note.fx);
+                    if bars.start == tune.notes.len() {
+                        bars.carry += note.duration as u64;
+                    }
+                }
+                _ => tune.notes.push(note),
+            }
+            tie = false;
+        }
+        if let Some(mark) = rest.state.mark.take() {
+            if matches!(mark, Mark::Bar | Mark::RepeatStart | Mark::RepeatEnd(_)) {
+                bars.check(&tune.notes, &mut tune.warnings);
+            }
+            let repeat_end = matches!(mark, Mark::RepeatEnd(_));
+            if matches!(mark, Mark::Tie) {
+                tie = true;
+            } else if let Err(reason) = apply_mark(mark, &mut tune, &mut blocks, &mut phrases) {
+                invalid = Some(reason);
+                break;
+            }
+            // the repeated passes were checked as they were written
+            if repeat_end {
+                bars.start = tune.notes.len();
+            }
         }
 
         input = rest;
     }
 
+    // fx with no note after them still apply, on a rest that takes no time
+    if !input.state.fx.is_empty() {
+        tune.notes.push(Note {
+            tone: '\0',
+            octave: octave(input.state.oct),
+            duration: 0,
+            fx: std::mem::take(&mut input.state.fx),
+            chord: Vec::new(),
+            velocity: 0,
+            legato: false,
+        });
+    }
+
+    if let Some(line) = input
+        .input
+        .lines()
+        .next()
+        .filter(|line| !line.trim().is_empty())
+    {
+        tune.warnings.push(match invalid {
+            Some(reason) => Warning::InvalidMark {
+                reason,
+                text: line.to_string(),
+            },
+            None => Warning::Unparsed(line.to_string()),
+        });
+    }
+    for block in &blocks {
+        tune.warnings.push(Warning::Unclosed(block.name()));
+    }
+
     Ok((input.input, tune))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_with_modulation_fx() {
+        let (rest, Tune { notes, .. }) = tune("C[fmindex 0.5][ring + 0.2]").unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(notes.len(), 1);
+        let fx = &notes[0].fx;
+        assert!(matches!(fx[0].command, FxCommand::Param(param) if param.name == "fmindex"));
+        assert_eq!(fx[0].val, 0.5);
+        assert!(matches!(fx[1].command, FxCommand::Param(param) if param.name == "ring"));
+        assert_eq!(fx[1].r#mod, '+');
+    }
+
+    #[test]
+    fn every_param_is_an_fx() {
+        for param in params::PARAMS {
+            let source = format!("[{} {}] C", param.name, param.default);
+            let (rest, Tune { notes, .. }) = tune(&source).unwrap();
+            assert!(rest.is_empty(), "unparsed: {rest:?}");
+            assert!(
+                matches!(notes[0].fx[..], [Fx { command: FxCommand::Param(fx), val, .. }]
+                    if fx.name == param.name && (val - param.default).abs() < 1e-5),
+                "{}",
+                param.name
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_fx_are_kept() {
+        let (rest, Tune { notes, .. }) = tune("C [volume 0.5]").unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(notes.len(), 2);
+        assert_eq!((notes[1].tone, notes[1].duration), ('\0', 0));
+        assert!(matches!(notes[1].fx[..], [Fx { val, .. }] if val == 0.5));
+    }
+
+    #[test]
+    fn ties_merge_fx() {
+        let (rest, Tune { notes, .. }) = tune("C [volume 0.5] ~ C").unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(notes.len(), 1);
+        assert!(matches!(notes[0].fx[..], [Fx { val, .. }] if val == 0.5));
+    }
+
+    #[test]
+    fn ramps_need_a_length() {
+        for length in ["inf", "nan", "0", "-2"] {
+            let source = format!("[lpf 0.2 -> 0.8 over {length}] C");
+            let (
+                rest,
+                Tune {
+                    notes, warnings, ..
+                },
+            ) = tune(&source).unwrap();
+            assert_eq!(rest, source, "{length}");
+            assert!(notes.is_empty());
+            assert_eq!(warnings, [Warning::Unparsed(source.clone())]);
+        }
+    }
+
+    #[test]
+    fn chords() {
+        for source in ["8(C E5 G)", "8C/E5/G"] {
+            let (rest, Tune { notes, .. }) = tune(source).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(notes.len(), 1);
+            assert_eq!((notes[0].tone, notes[0].octave), ('C', 4));
+            assert_eq!(notes[0].duration, TICKS_PER_WHOLE / 8);
+            assert_eq!(notes[0].chord, vec![('E', 5), ('G', 5)]);
+        }
+    }
+
+    fn tones(source: &str) -> String {
+        let (rest, tune) = tune(source).unwrap();
+        assert!(rest.is_empty(), "unparsed: {rest:?}");
+        tune.notes.iter().map(|note| note.tone).collect()
+    }
+
+    #[test]
+    fn repeats_and_endings() {
+        assert_eq!(tones("|: C D :|x3 E"), "CDCDCDE");
+        assert_eq!(tones("|: C [1 D] [2 E] :| F"), "CDCEF");
+        assert_eq!(tones("|: C |: D :| :|"), "CDDCDD");
+    }
+
+    #[test]
+    fn phrases_and_loop() {
+        assert_eq!(tones("$a = C D; $b = E; $a $b $a"), "CDECD");
+        let (_, looped) = tune("C [loop] D E").unwrap();
+        assert_eq!(looped.loop_start, Some(1));
+        assert!(!tune("$missing C").unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn invalid_structure_warns() {
+        let warnings = |source| tune(source).unwrap().1.warnings;
+        assert_eq!(
+            warnings("C :| D"),
+            [Warning::InvalidMark {
+                reason: "repeat end without a start",
+                text: ":| D".to_string()
+            }]
+        );
+        assert_eq!(warnings("|: C D"), [Warning::Unclosed("repeat")]);
+        assert_eq!(
+            warnings("$a = C D"),
+            [Warning::Unclosed("phrase definition")]
+        );
+
+        // counts that are too large fail to parse rather than panic or expand
+        assert_eq!(tones("|: C :|x99 D").len(), 100);
+        assert_eq!(
+            warnings("|: C :|x99999999999999999999999"),
+            [Warning::Unparsed("x99999999999999999999999".to_string())]
+        );
+        assert!(matches!(
+            &warnings("|: |: |: C :|x99 :|x99 :|x99")[..],
+            [Warning::InvalidMark {
+                reason: "too many notes",
+                ..
+            }]
+        ));
+    }
+
+    fn durations(source: &str) -> Vec<u32> {
+        let (rest, tune) = tune(source).unwrap();
+        assert!(rest.is_empty(), "unparsed: {rest:?}");
+        tune.notes.iter().map(|note| note.duration).collect()
+    }
+
+    #[test]
+    fn dots_ties_and_tuplets() {
+        let quarter = TICKS_PER_WHOLE / 4;
+        assert_eq!(
+            durations("4.:C 8..D"),
+            vec![quarter * 3 / 2, quarter * 7 / 8]
+        );
+        assert_eq!(durations("C~C 2:D"), vec![quarter * 2, quarter * 2]);
+        assert_eq!(durations("(C E)~(C E)"), vec![quarter * 2]);
+        assert_eq!(durations("3{C D E}"), vec![quarter * 2 / 3; 3]);
+        assert_eq!(
+            durations("5{8:C 8:D 8:E 8:F 8:G}").iter().sum::<u32>(),
+            quarter * 2
+        );
+    }
+
+    #[test]
+    fn invalid_dots_and_ties_warn() {
+        // at most two dots, the third is left unparsed instead of overflowing the length
+        let (
+            rest,
+            Tune {
+                notes, warnings, ..
+            },
+        ) = tune("4...:C").unwrap();
+        assert_eq!(rest, ".:C");
+        assert_eq!(notes.len(), 1);
+        assert_eq!(warnings, [Warning::Unparsed(".:C".to_string())]);
+        assert!(!tune(&format!("4{}:C", ".".repeat(70)))
+            .unwrap()
+            .0
+            .is_empty());
+
+        let (
+            _,
+            Tune {
+                notes, warnings, ..
+            },
+        ) = tune("C D~E |").unwrap();
+        assert_eq!(notes.len(), 3);
+        assert_eq!(warnings, [Warning::MismatchedTie { bar: 1 }]);
+    }
+
+    #[test]
+    fn tempo_and_time_directives() {
+        let (rest, Tune { notes, .. }) =
+            tune("[time 3/4] [tempo 180] C [tempo +10] D [accel 200 over 4] E 4 [rit - 20]")
+                .unwrap();
+        assert!(rest.is_empty(), "unparsed: {rest:?}");
+        assert!(matches!(notes[0].fx[0].command, FxCommand::Time(3, 4)));
+        assert!(matches!(notes[0].fx[1].command, FxCommand::Tempo));
+        assert_eq!((notes[1].fx[0].r#mod, notes[1].fx[0].val), ('+', 10.0));
+        assert_eq!(notes[2].fx[0].over, Some(4.0));
+        assert!(notes[3].fx.is_empty());
+        assert_eq!(notes[4].duration, 0);
+        assert_eq!((notes[4].fx[0].r#mod, notes[4].fx[0].val), ('-', 20.0));
+        assert_eq!(notes.len(), 5);
+    }
+
+    #[test]
+    fn automation_ramps() {
+        let (rest, Tune { notes, .. }) =
+            tune("[lpf 0.2 -> 0.8 over 4] C [cutoff 200->4000 over 2.5 exp] D [q 2 over 1] E")
+                .unwrap();
+        assert!(rest.is_empty(), "unparsed: {rest:?}");
+        let fx: Vec<_> = notes.iter().map(|note| &note.fx[0]).collect();
+        assert_eq!(
+            (fx[0].from, fx[0].val, fx[0].over),
+            (Some(0.2), 0.8, Some(4.0))
+        );
+        assert!(!fx[0].exponential);
+        assert_eq!(
+            (fx[1].from, fx[1].val, fx[1].over),
+            (Some(200.0), 4000.0, Some(2.5))
+        );
+        assert!(fx[1].exponential);
+        assert_eq!((fx[2].from, fx[2].val, fx[2].over), (None, 2.0, Some(1.0)));
+    }
+
+    #[test]
+    fn keys_and_transposition() {
+        assert_eq!(tones("[key D] F C G C= F#"), "gdGCg");
+        assert_eq!(tones("[key Bbm] B E A D G C"), "beadgC");
+        assert_eq!(tones("E# Fb [transpose +2] A B"), "FEBd");
+
+        let (_, Tune { notes, .. }) = tune("B#3 Cb4 [transpose -1] C4").unwrap();
+        let pitches: Vec<_> = notes.iter().map(|note| (note.tone, note.octave)).collect();
+        assert_eq!(pitches, [('C', 4), ('B', 3), ('B', 3)]);
+    }
+
+    #[test]
+    fn pitches_stop_at_the_playable_range() {
+        for (source, expected) in [
+            ("C1 - - C", ('C', 1)),
+            ("B8 + + B", ('B', 8)),
+            ("[transpose -12] C1", ('C', 1)),
+            ("[transpose -127] C", ('C', 1)),
+            ("[transpose +99999999999] C", ('B', 8)),
+        ] {
+            let (rest, Tune { notes, .. }) = tune(source).unwrap();
+            assert!(rest.is_empty(), "unparsed: {rest:?}");
+            let last = notes.last().unwrap();
+            assert_eq!((last.tone, last.octave), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn comments_and_bar_lines() {
+        let source = "# intro
+            [time 3/4] 8:G | C D E ~ // held over the bar line
+            | E 2:F | 2:G 8:A 8:B | C C C C |
+            8:C 8:D";
+        let (
+            rest,
+            Tune {
+                notes, warnings, ..
+            },
+        ) = tune(source).unwrap();
+        assert!(rest.is_empty(), "unparsed: {rest:?}");
+        assert_eq!(notes.len(), 14);
+        assert_eq!(
+            warnings,
+            [Warning::BarLength {
+                bar: 5,
+                beats: 4.0,
+                expected: 3.0
+            }]
+        );
+
+        let (_, Tune { warnings, .. }) = tune("|: C C C C :| D D D D |").unwrap();
+        assert_eq!(warnings, []);
+
+        let (_, Tune { warnings, .. }) = tune("C D ) E").unwrap();
+        assert_eq!(warnings, [Warning::Unparsed(") E".to_string())]);
+    }
+
+    #[test]
+    fn instrument_switch() {
+        let (rest, Tune { notes, .. }) =
+            tune("[inst bass] C [inst snare-2][volume 0.3] D").unwrap();
+        assert!(rest.is_empty(), "unparsed: {rest:?}");
+        assert!(matches!(&notes[0].fx[0].command, FxCommand::Instrument(name) if name == "bass"));
+        assert!(
+            matches!(&notes[1].fx[0].command, FxCommand::Instrument(name) if name == "snare-2")
+        );
+        assert!(
+            matches!(notes[1].fx[1].command, FxCommand::Param(param) if param.name == "volume")
+        );
+    }
+
+    #[test]
+    fn velocity_and_dynamics() {
+        let velocities = |source| {
+            let (rest, Tune { notes, .. }) = tune(source).unwrap();
+            assert!(rest.is_empty(), "unparsed: {rest:?}");
+            notes.iter().map(|note| note.velocity).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            velocities("C C! (C E)? C@80 C@200"),
+            [100, 120, 50, 80, 127]
+        );
+        assert_eq!(
+            velocities("[cresc 20] C C C [/cresc] [dim 30] C! 4 C! [/dim]"),
+            [100, 110, 120, 120, 0, 90]
+        );
+    }
+
+    #[test]
+    fn slurs() {
+        let (rest, Tune { notes, .. }) = tune("C_D E_ [glide 0.2] F 4 _G").unwrap();
+        assert!(rest.is_empty(), "unparsed: {rest:?}");
+        let legato: Vec<_> = notes.iter().map(|note| note.legato).collect();
+        assert_eq!(legato, [false, true, false, true, false, true]);
+        assert!(matches!(notes[3].fx[0].command, FxCommand::Param(param) if param.name == "glide"));
+    }
+}
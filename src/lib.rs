@@ -1,26 +1,180 @@
+use std::{fmt, fs, io, path::Path, str::FromStr, sync::Arc};
+
+mod generators;
 mod internal;
+pub mod params;
+mod song;
+mod units;
+mod wav;
 
 const MAX_CHANNELS: usize = 8;
+const MASTER_VOLUME: f32 = 0.10;
 const UNUSED_CHANNEL: Option<Rustaphone> = None;
 
 pub struct Rustaphone {
     internal: internal::Rustaphone,
+    warnings: Vec<Warning>,
+    /// Everything needed to write the song back out.
+    song: song::Song,
 }
 
 impl Rustaphone {
     pub fn new() -> Rustaphone {
         Rustaphone {
-            internal: internal::Rustaphone::new(120, 0.10),
+            internal: internal::Rustaphone::new(120, MASTER_VOLUME),
+            warnings: Vec::new(),
+            song: song::Song::default(),
+        }
+    }
+
+    /// Loads a song from its text format:
+    ///
+    /// ```text
+    /// # comments start with a hash
+    /// title = Boss fight
+    /// tempo = 160
+    ///
+    /// [instrument lead]
+    /// waveform = pulse25
+    /// volume = 0.4
+    /// decay = 0.2
+    ///
+    /// [track melody]
+    /// instrument = lead
+    /// polyphony = 2
+    /// tune = [key D] C D E F |
+    /// tune = G A B C |
+    /// ```
+    ///
+    /// Instruments take every parameter of [`InstrumentBuilder`] by name, with wavetables and
+    /// samples written inline as comma-separated values. Numeric values outside the range of
+    /// their parameter (see [`params::PARAMS`]) are clamped to it. Modulation takes one line per LFO
+    /// and route, as in `lfo = sine 5 hz` or `lfo = triangle 2 beats` and
+    /// `modulation = lfo1 pitch 0.5` or `modulation = envelope cutoff 2`. Repeated `tune` lines
+    /// are joined with newlines.
+    pub fn from_song_str(source: &str) -> Result<Rustaphone, Error> {
+        let song = song::parse_song(source)?;
+        if song.tracks.len() > internal::MAX_TRACKS {
+            return Err(Error::InvalidSong {
+                line: 0,
+                reason: format!("more than {} tracks", internal::MAX_TRACKS),
+            });
+        }
+
+        let mut rustaphone = Rustaphone::new();
+        if let Some(tempo) = song.tempo {
+            rustaphone.internal.tempo(tempo);
         }
+        for (name, instrument) in &song.instruments {
+            rustaphone.internal.add_instrument(name, instrument.clone());
+        }
+        for track in &song.tracks {
+            let (_, instrument) = song
+                .instruments
+                .iter()
+                .find(|(name, _)| *name == track.instrument)
+                .unwrap();
+            let track = internal::Track::new(instrument.clone(), &track.tune)
+                .with_polyphony(track.polyphony);
+            rustaphone.add_warnings(&track);
+            rustaphone.internal.add_track(track);
+        }
+        rustaphone.song = song;
+
+        Ok(rustaphone)
+    }
+
+    pub fn from_song_file(path: impl AsRef<Path>) -> Result<Rustaphone, Error> {
+        Rustaphone::from_song_str(&fs::read_to_string(path)?)
     }
 
+    /// Writes the song out in the format read by [`Rustaphone::from_song_str`].
+    pub fn to_song_string(&self) -> String {
+        song::write_song(&self.song)
+    }
+
+    pub fn save_song_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        Ok(fs::write(path, self.to_song_string())?)
+    }
+
+    pub fn title(&mut self, title: &str) {
+        self.song.title = Some(title.to_string());
+    }
+
+    /// Sets the tempo in beats per minute, kept from 1 to 1000 like the `[tempo]` directive.
     pub fn tempo(&mut self, tempo: i32) {
+        let tempo = tempo.clamp(1, 1000);
         self.internal.tempo(tempo);
+        self.song.tempo = Some(tempo);
+    }
+
+    /// Sets the length of the short fades that keep note changes and stops from clicking.
+    /// Zero turns them off.
+    pub fn declick(&mut self, seconds: f32) {
+        self.internal.declick(seconds);
+    }
+
+    /// Shifts every track by a number of semitones, starting with the next note each plays.
+    pub fn transpose(&mut self, semitones: i32) {
+        self.internal.transpose(semitones);
+    }
+
+    /// Registers an instrument that tracks can switch to with `[inst name]`. Adding another
+    /// instrument under the same name replaces it.
+    pub fn add_instrument(&mut self, name: &str, instrument: Instrument) {
+        self.internal.add_instrument(name, instrument.clone());
+        self.warnings
+            .retain(|warning| !matches!(warning, Warning::UnknownInstrument(n) if n == name));
+        match self.song.instruments.iter_mut().find(|(n, _)| n == name) {
+            Some((_, old)) => *old = instrument,
+            None => self.song.instruments.push((name.to_string(), instrument)),
+        }
     }
 
     pub fn add_track(&mut self, instrument: Instrument, tune: &str) {
-        let track = internal::Track::new(instrument, tune);
+        self.add_track_with_polyphony(instrument, tune, internal::DEFAULT_POLYPHONY);
+    }
+
+    /// Adds a track that plays at most `polyphony` notes of a chord at the same time.
+    pub fn add_track_with_polyphony(
+        &mut self,
+        instrument: Instrument,
+        tune: &str,
+        polyphony: usize,
+    ) {
+        let track = internal::Track::new(instrument.clone(), tune).with_polyphony(polyphony);
+        self.add_warnings(&track);
         self.internal.add_track(track);
+
+        let number = self.song.tracks.len() + 1;
+        // skip names already taken by registered instruments
+        let name = (number..)
+            .map(|n| format!("instrument{n}"))
+            .find(|name| self.song.instruments.iter().all(|(n, _)| n != name))
+            .unwrap();
+        self.song.tracks.push(song::Track {
+            name: format!("track{number}"),
+            instrument: name.clone(),
+            polyphony,
+            tune: tune.to_string(),
+        });
+        self.song.instruments.push((name, instrument));
+    }
+
+    /// Problems found in the tunes added so far, such as bars that don't match the time
+    /// signature.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    fn add_warnings(&mut self, track: &internal::Track) {
+        self.warnings.extend_from_slice(track.warnings());
+        for name in track.instruments() {
+            let warning = Warning::UnknownInstrument(name.to_string());
+            if !self.internal.has_instrument(name) && !self.warnings.contains(&warning) {
+                self.warnings.push(warning);
+            }
+        }
     }
 }
 
@@ -34,17 +188,51 @@ pub struct StopHandle {
     channel: usize,
 }
 
+/// Identifies a note started with [`Mixer::note_on`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceHandle {
+    slot: usize,
+    generation: u32,
+}
+
 pub struct Mixer {
     channels: [Option<Rustaphone>; MAX_CHANNELS],
+    live: internal::Live,
+    /// Rate of the last [`Mixer::synth`] call, to size the anti-click ramps of live notes.
+    sample_rate: u32,
 }
 
 impl Mixer {
     pub fn new() -> Self {
         Mixer {
             channels: [UNUSED_CHANNEL; MAX_CHANNELS],
+            live: internal::Live::new(MASTER_VOLUME),
+            sample_rate: 44100,
         }
     }
 
+    fn ramp(&self) -> i32 {
+        (internal::DECLICK_TIME * self.sample_rate as f32) as i32
+    }
+
+    /// Starts playing `pitch` (a MIDI note number from 0 to 127, 60 is middle C) until
+    /// [`Mixer::note_off`] is called. `velocity` goes from 0 to 127, 100 plays at the
+    /// instrument's own volume.
+    ///
+    /// When all live voices are busy the oldest note is cut short to make room.
+    pub fn note_on(&mut self, instrument: &Instrument, pitch: u8, velocity: u8) -> VoiceHandle {
+        let (slot, generation) = self.live.note_on(instrument, pitch, velocity, self.ramp());
+        VoiceHandle { slot, generation }
+    }
+
+    /// Releases a held note, which then fades out over the instrument's decay, or over its
+    /// release stage when the ADSR envelope is on. Without a decay or release to run it fades
+    /// out quickly instead. Returns false if the note had already ended.
+    pub fn note_off(&mut self, handle: VoiceHandle) -> bool {
+        let ramp = self.ramp();
+        self.live.note_off(handle.slot, handle.generation, ramp)
+    }
+
     pub fn play(&mut self, mut rustaphone: Rustaphone) -> Option<StopHandle> {
         rustaphone.internal.play();
 
@@ -68,19 +256,75 @@ impl Mixer {
         self.is_done()
     }
 
-    pub fn is_done(&self) -> bool {
-        self.channels.iter().all(|channel| {
-            if let Some(channel) = channel {
-                channel.internal.is_done()
-            } else {
+    /// Replaces the instrument of a track (counting from zero) in a playing tune, for example
+    /// to morph between two sounds with [`Instrument::lerp`]. The playing note keeps its pitch
+    /// and the arpeggio, phaser and repeat settings apply from the next note on, everything
+    /// else changes right away. Returns false if there is no such track.
+    pub fn set_instrument(
+        &mut self,
+        handle: &StopHandle,
+        track: usize,
+        instrument: &Instrument,
+    ) -> bool {
+        match &mut self.channels[handle.channel] {
+            Some(channel) => channel.internal.set_params(track, &instrument.params),
+            None => false,
+        }
+    }
+
+    /// Shifts every track of a playing tune by a number of semitones, like
+    /// [`Rustaphone::transpose`], starting with the next note each track plays. Returns false if
+    /// the tune is no longer playing.
+    pub fn transpose(&mut self, handle: &StopHandle, semitones: i32) -> bool {
+        match &mut self.channels[handle.channel] {
+            Some(channel) if !channel.internal.is_done() => {
+                channel.internal.transpose(semitones);
                 true
             }
+            _ => false,
+        }
+    }
+
+    /// Changes a parameter (see [`params::PARAMS`]) of a track in a playing tune, for sounds
+    /// steered by the game such as engines or sirens. The change is smoothed by a one-pole
+    /// filter with the declick time as its time constant so it can be called every frame
+    /// without zipper noise, and lasts until the tune is played again. Returns false if there
+    /// is no such track.
+    ///
+    /// `freq` sets the pitch of the note that is playing, the next note in the tune sets its
+    /// own again. Slides, glides and sweeps keep going while a change is smoothed.
+    pub fn set_param(
+        &mut self,
+        handle: &StopHandle,
+        track: usize,
+        name: &str,
+        value: f32,
+    ) -> Result<bool, Error> {
+        let param = params::param(name).ok_or_else(|| Error::UnknownParam(name.to_string()))?;
+        Ok(match &mut self.channels[handle.channel] {
+            Some(channel) => {
+                let sample_rate = self.sample_rate;
+                channel.internal.set_param(track, param, value, sample_rate)
+            }
+            None => false,
         })
     }
 
+    pub fn is_done(&self) -> bool {
+        self.live.is_silent()
+            && self.channels.iter().all(|channel| {
+                if let Some(channel) = channel {
+                    channel.internal.is_done()
+                } else {
+                    true
+                }
+            })
+    }
+
     pub fn synth(&mut self, sample_rate: u32, buffer: &mut [f32]) {
+        self.sample_rate = sample_rate;
         for sample in buffer {
-            let mut allsample = 0.0;
+            let mut allsample = self.live.synth(sample_rate);
 
             for c in 0..MAX_CHANNELS {
                 let Some(channel) = &mut self.channels[c] else {
@@ -126,6 +370,28 @@ impl Instrument {
 
         Instrument { params }
     }
+
+    pub fn sample(sample: Sample) -> Self {
+        let params = internal::Params {
+            r#type: Waveform::Sample(sample),
+            ..Default::default()
+        };
+
+        Instrument { params }
+    }
+
+    /// Reads a numeric parameter by its name in [`params::PARAMS`].
+    pub fn get(&self, name: &str) -> Option<f32> {
+        params::param(name).map(|param| param.get(&self.params))
+    }
+
+    /// Sets a numeric parameter by its name in [`params::PARAMS`], clamped to its range.
+    pub fn set(&mut self, name: &str, value: f32) -> Result<(), Error> {
+        let param = params::param(name).ok_or_else(|| Error::UnknownParam(name.to_string()))?;
+        param.set(&mut self.params, value);
+
+        Ok(())
+    }
 }
 
 pub struct InstrumentBuilder {
@@ -268,14 +534,551 @@ impl InstrumentBuilder {
         self.params.repeat = repeat;
         self
     }
+
+    pub fn with_filter(mut self, filter: FilterMode) -> InstrumentBuilder {
+        self.params.filter = filter;
+        self
+    }
+
+    pub fn with_slope(mut self, slope: FilterSlope) -> InstrumentBuilder {
+        self.params.slope = slope;
+        self
+    }
+
+    /// Sets the cutoff (or center) frequency of the multimode filter in Hz.
+    pub fn with_cutoff(mut self, cutoff: f32) -> InstrumentBuilder {
+        self.params.cutoff = cutoff;
+        self
+    }
+
+    /// Sets the resonance of the multimode filter as a Q factor.
+    pub fn with_q(mut self, q: f32) -> InstrumentBuilder {
+        self.params.q = q;
+        self
+    }
+
+    /// Sets how many octaves the envelope moves the multimode filter cutoff at full level.
+    pub fn with_fenv(mut self, fenv: f32) -> InstrumentBuilder {
+        self.params.fenv = fenv;
+        self
+    }
+
+    pub fn with_fmratio(mut self, fmratio: f32) -> InstrumentBuilder {
+        self.params.fmratio = fmratio;
+        self
+    }
+
+    pub fn with_fmindex(mut self, fmindex: f32) -> InstrumentBuilder {
+        self.params.fmindex = fmindex;
+        self
+    }
+
+    pub fn with_fmattack(mut self, fmattack: f32) -> InstrumentBuilder {
+        self.params.fmattack = fmattack;
+        self
+    }
+
+    pub fn with_fmdecay(mut self, fmdecay: f32) -> InstrumentBuilder {
+        self.params.fmdecay = fmdecay;
+        self
+    }
+
+    pub fn with_ring(mut self, ring: f32) -> InstrumentBuilder {
+        self.params.ring = ring;
+        self
+    }
+
+    pub fn with_rratio(mut self, rratio: f32) -> InstrumentBuilder {
+        self.params.rratio = rratio;
+        self
+    }
+
+    /// Sets the portamento time, sliding each note up or down from the pitch of the previous
+    /// one.
+    pub fn with_glide(mut self, glide: f32) -> InstrumentBuilder {
+        self.params.glide = glide;
+        self
+    }
+
+    /// Plays every note without retriggering the envelope while the previous one still sounds.
+    pub fn with_legato(mut self, legato: bool) -> InstrumentBuilder {
+        self.params.legato = legato;
+        self
+    }
+
+    /// Swaps the sfxr envelope for attack, decay, sustain and release, where `attack` and
+    /// `decay` keep their meaning, the sustain holds at `slevel` for as long as the note lasts
+    /// and the release starts when it ends.
+    ///
+    /// A track plays one note at a time, so the release only runs out at rests, after the last
+    /// note and for live notes. A note that follows straight on retriggers the envelope and cuts
+    /// the release of the one before short.
+    pub fn with_adsr(mut self, adsr: bool) -> InstrumentBuilder {
+        self.params.adsr = adsr;
+        self
+    }
+
+    pub fn with_slevel(mut self, slevel: f32) -> InstrumentBuilder {
+        self.params.slevel = slevel;
+        self
+    }
+
+    pub fn with_release(mut self, release: f32) -> InstrumentBuilder {
+        self.params.release = release;
+        self
+    }
+
+    /// Adds a low-frequency oscillator, which [`ModSource::Lfo`] refers to by the order the
+    /// LFOs were added in, counting from zero. They restart with every note that restarts the
+    /// envelope.
+    pub fn with_lfo(mut self, shape: LfoShape, rate: LfoRate) -> InstrumentBuilder {
+        self.params.lfos.push((shape, rate));
+        self
+    }
+
+    /// Routes a modulation source to a target, scaled by `depth` in the units of the target.
+    /// Several routes to the same target add up.
+    pub fn with_modulation(
+        mut self,
+        source: ModSource,
+        target: ModTarget,
+        depth: f32,
+    ) -> InstrumentBuilder {
+        self.params.modulation.push((source, target, depth));
+        self
+    }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Waveform {
     Square,
     Sawtooth,
     Sine,
     Noise,
+    Triangle,
+    /// The 4-bit stepped triangle of the NES triangle channel.
+    NesTriangle,
+    /// A square wave locked to one of the NES pulse channel duty cycles. Tunes can switch
+    /// between them with `[duty 0.25]`, which does nothing for the other waveforms.
+    Pulse(Duty),
+    /// Noise from a 15-bit LFSR, like the NES noise channel.
+    PeriodicNoise(NoiseMode),
+    /// A user-defined single-cycle waveform.
+    Wavetable(Wavetable),
+    /// A recorded sample, resampled to the pitch of each note.
+    Sample(Sample),
+}
+
+impl Waveform {
+    fn name(&self) -> &'static str {
+        match self {
+            Waveform::Square => "square",
+            Waveform::Sawtooth => "sawtooth",
+            Waveform::Sine => "sine",
+            Waveform::Noise => "noise",
+            Waveform::Triangle => "triangle",
+            Waveform::NesTriangle => "nestriangle",
+            Waveform::Pulse(Duty::Eighth) => "pulse12",
+            Waveform::Pulse(Duty::Quarter) => "pulse25",
+            Waveform::Pulse(Duty::Half) => "pulse50",
+            Waveform::Pulse(Duty::ThreeQuarters) => "pulse75",
+            Waveform::PeriodicNoise(NoiseMode::Long) => "periodic",
+            Waveform::PeriodicNoise(NoiseMode::Short) => "metallic",
+            Waveform::Wavetable(_) => "wavetable",
+            Waveform::Sample(_) => "sample",
+        }
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parses the names written by `Display`, except for `wavetable` and `sample`, which fail with
+/// [`Error::WaveformData`] as the name alone doesn't say what to play.
+impl FromStr for Waveform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let waveform = match s {
+            "square" => Waveform::Square,
+            "sawtooth" => Waveform::Sawtooth,
+            "sine" => Waveform::Sine,
+            "noise" => Waveform::Noise,
+            "triangle" => Waveform::Triangle,
+            "nestriangle" => Waveform::NesTriangle,
+            "pulse12" => Waveform::Pulse(Duty::Eighth),
+            "pulse25" => Waveform::Pulse(Duty::Quarter),
+            "pulse50" => Waveform::Pulse(Duty::Half),
+            "pulse75" => Waveform::Pulse(Duty::ThreeQuarters),
+            "periodic" => Waveform::PeriodicNoise(NoiseMode::Long),
+            "metallic" => Waveform::PeriodicNoise(NoiseMode::Short),
+            "wavetable" | "sample" => return Err(Error::WaveformData(s.to_string())),
+            _ => return Err(Error::UnknownWaveform(s.to_string())),
+        };
+
+        Ok(waveform)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Duty {
+    /// 12.5%
+    Eighth,
+    /// 25%
+    Quarter,
+    /// 50%
+    Half,
+    /// 75%
+    ThreeQuarters,
+}
+
+impl Duty {
+    pub fn ratio(self) -> f32 {
+        match self {
+            Duty::Eighth => 0.125,
+            Duty::Quarter => 0.25,
+            Duty::Half => 0.5,
+            Duty::ThreeQuarters => 0.75,
+        }
+    }
+
+    /// Snaps an arbitrary duty ratio to the closest available duty cycle.
+    pub fn nearest(ratio: f32) -> Duty {
+        [Duty::Eighth, Duty::Quarter, Duty::Half, Duty::ThreeQuarters]
+            .into_iter()
+            .min_by(|a, b| f32::abs(a.ratio() - ratio).total_cmp(&f32::abs(b.ratio() - ratio)))
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseMode {
+    /// 32767 step sequence, sounds like white noise.
+    Long,
+    /// 93 step sequence, gives the metallic buzz.
+    Short,
+}
+
+/// Selects the filter applied to the oscillator output.
+///
+/// `Legacy` is the sfxr low-pass/high-pass chain driven by `lpf`, `resonance` and `hpf`; the
+/// other modes use a state-variable filter driven by `cutoff`, `q` and `fenv` instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FilterMode {
+    #[default]
+    Legacy,
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FilterSlope {
+    #[default]
+    Db12,
+    Db24,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Square,
+    /// A new random value once per cycle.
+    SampleAndHold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LfoRate {
+    Hz(f32),
+    /// Length of a cycle in beats of the tune, following its tempo. Live notes count 120 beats
+    /// per minute.
+    Beats(f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModSource {
+    /// One of the LFOs added with [`InstrumentBuilder::with_lfo`], swinging between -1 and 1.
+    Lfo(usize),
+    /// The volume envelope, from 0 to 1.
+    Envelope,
+}
+
+/// What a modulation route moves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModTarget {
+    /// Moves the pitch by `depth` semitones.
+    Pitch,
+    /// Scales the volume by `1 + depth`.
+    Volume,
+    /// Moves the multimode filter cutoff by `depth` octaves, or the legacy low-pass one when
+    /// `lpf` turns it on.
+    Cutoff,
+    /// Widens the duty cycle of square and pulse waves by `depth`, where 0.5 is half a cycle.
+    Duty,
+    /// Moves the phaser offset by `depth` times its full range.
+    Phaser,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Interpolation {
+    #[default]
+    None,
+    Linear,
+    Cubic,
+}
+
+/// A single cycle of a waveform, played back once per period.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wavetable {
+    samples: Arc<[f32]>,
+    interpolation: Interpolation,
+}
+
+impl Wavetable {
+    pub fn new(samples: impl Into<Arc<[f32]>>) -> Self {
+        Wavetable {
+            samples: samples.into(),
+            interpolation: Interpolation::None,
+        }
+    }
+
+    /// Builds a table from 4-bit levels (0 to 15), like the 32 samples of the Game Boy wave
+    /// channel.
+    pub fn from_4bit(levels: &[u8]) -> Self {
+        let samples: Vec<f32> = levels
+            .iter()
+            .map(|level| (level & 0x0f) as f32 / 7.5 - 1.0)
+            .collect();
+        Wavetable::new(samples)
+    }
+
+    /// Uses the contents of a WAV file as the single cycle.
+    pub fn from_wav(bytes: &[u8]) -> Result<Self, Error> {
+        let (_, samples) = wav::decode(bytes)?;
+        Ok(Wavetable::new(samples))
+    }
+
+    pub fn from_wav_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Wavetable::from_wav(&fs::read(path)?)
+    }
+
+    pub fn with_interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// Returns the table value at `fp`, the position within the period from 0 to 1.
+    fn sample(&self, fp: f32) -> f32 {
+        let len = self.samples.len();
+        if len == 0 {
+            return 0.0;
+        }
+
+        let pos = fp * len as f32;
+        let i = pos as usize % len;
+        let t = pos.fract();
+        let at = |offset: usize| self.samples[(i + offset) % len];
+
+        match self.interpolation {
+            Interpolation::None => at(0),
+            Interpolation::Linear => at(0) + (at(1) - at(0)) * t,
+            Interpolation::Cubic => {
+                // Catmull-Rom through the neighbouring samples, wrapping around the cycle
+                let (p0, p1, p2, p3) = (at(len - 1), at(0), at(1), at(2));
+                p1 + 0.5
+                    * t
+                    * (p2 - p0
+                        + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3
+                            + t * (3.0 * (p1 - p2) + p3 - p0)))
+            }
+        }
+    }
+}
+
+/// Middle C, the default root note of a [`Sample`].
+const SAMPLE_ROOT: f32 = 261.63;
+
+/// A PCM recording used as the oscillator of an instrument.
+///
+/// The sample plays back at its original speed for notes at the root pitch and is resampled
+/// for everything else.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    data: Arc<[f32]>,
+    sample_rate: u32,
+    root: f32,
+    loop_points: Option<(usize, usize)>,
+}
+
+impl Sample {
+    pub fn new(data: impl Into<Arc<[f32]>>, sample_rate: u32) -> Self {
+        Sample {
+            data: data.into(),
+            sample_rate,
+            root: SAMPLE_ROOT,
+            loop_points: None,
+        }
+    }
+
+    pub fn from_wav(bytes: &[u8]) -> Result<Self, Error> {
+        let (sample_rate, data) = wav::decode(bytes)?;
+        Ok(Sample::new(data, sample_rate))
+    }
+
+    pub fn from_wav_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Sample::from_wav(&fs::read(path)?)
+    }
+
+    /// Sets the pitch (in Hz) the sample was recorded at.
+    pub fn with_root(mut self, root: f32) -> Self {
+        self.root = root;
+        self
+    }
+
+    /// Loops the frames from `start` up to (excluding) `end` once playback reaches `end`.
+    pub fn with_loop(mut self, start: usize, end: usize) -> Self {
+        let end = usize::min(end, self.data.len());
+        self.loop_points = (start < end).then_some((start, end));
+        self
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Returns the number of source frames to advance per oversampled step for a voice
+    /// running at `period`.
+    fn step(&self, period: f32) -> f64 {
+        // a voice plays 8 steps per output sample, so its pitch is 8 * rate / period
+        self.sample_rate as f64 / (period as f64 * self.root as f64)
+    }
+
+    fn sample(&self, pos: f64) -> f32 {
+        let i = pos as usize;
+        let Some(&current) = self.data.get(i) else {
+            return 0.0;
+        };
+        let next = match self.loop_points {
+            Some((start, end)) if i + 1 >= end => self.data[start],
+            _ => self.data.get(i + 1).copied().unwrap_or(0.0),
+        };
+
+        current + (next - current) * pos.fract() as f32
+    }
+
+    fn advance(&self, pos: f64, step: f64) -> f64 {
+        let pos = pos + step;
+        match self.loop_points {
+            Some((start, end)) if pos >= end as f64 => {
+                start as f64 + (pos - end as f64) % (end - start) as f64
+            }
+            _ => pos,
+        }
+    }
+}
+
+/// Something in a tune that looks like a mistake but doesn't stop it from playing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// A bar (counting from one) holds more or fewer beats than its time signature asks for.
+    BarLength {
+        bar: usize,
+        beats: f32,
+        expected: f32,
+    },
+    /// A tie in a bar (counting from one) joins two different pitches, so the second note is
+    /// played on its own.
+    MismatchedTie { bar: usize },
+    /// Parsing stopped before the end of the tune, at the start of this text.
+    Unparsed(String),
+    /// `[inst name]` names an instrument that isn't registered with
+    /// [`Rustaphone::add_instrument`], so the track keeps its current one.
+    UnknownInstrument(String),
+    /// Parsing stopped at a mark that doesn't fit where it is, such as `:|` without `|:` or an
+    /// unknown phrase, at the start of `text`.
+    InvalidMark { reason: &'static str, text: String },
+    /// A repeat, phrase definition, tuplet or dynamic range is still open at the end.
+    Unclosed(&'static str),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::BarLength {
+                bar,
+                beats,
+                expected,
+            } => write!(f, "bar {bar} has {beats} beats instead of {expected}"),
+            Warning::MismatchedTie { bar } => {
+                write!(f, "bar {bar} ties two different pitches")
+            }
+            Warning::Unparsed(rest) => write!(f, "could not parse tune from: {rest}"),
+            Warning::UnknownInstrument(name) => write!(f, "unknown instrument: {name}"),
+            Warning::InvalidMark { reason, text } => write!(f, "{reason} at: {text}"),
+            Warning::Unclosed(block) => write!(f, "{block} left open at the end of the tune"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownWaveform(String),
+    /// A waveform name, such as `wavetable` or `sample`, that needs data besides the name.
+    WaveformData(String),
+    UnknownParam(String),
+    InvalidWav(&'static str),
+    /// A song file could not be read, `line` counts from one or is zero for the whole file.
+    InvalidSong {
+        line: usize,
+        reason: String,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownWaveform(name) => write!(f, "unknown waveform: {name}"),
+            Error::WaveformData(name) => {
+                write!(f, "the {name} waveform can't be made from its name")
+            }
+            Error::UnknownParam(name) => write!(f, "unknown parameter: {name}"),
+            Error::InvalidWav(reason) => write!(f, "invalid WAV file: {reason}"),
+            Error::InvalidSong { line: 0, reason } => write!(f, "invalid song: {reason}"),
+            Error::InvalidSong { line, reason } => {
+                write!(f, "invalid song at line {line}: {reason}")
+            }
+            Error::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
 }
 
 #[cfg(test)]
@@ -284,4 +1087,429 @@ mod tests {
 
     #[test]
     fn it_works() {}
+
+    fn render(rustaphone: Rustaphone, frames: usize) -> Vec<f32> {
+        let mut mixer = Mixer::new();
+        mixer.play(rustaphone);
+        synth(&mut mixer, frames)
+    }
+
+    fn synth(mixer: &mut Mixer, frames: usize) -> Vec<f32> {
+        let mut buffer = vec![0.0; frames];
+        mixer.synth(44100, &mut buffer);
+        buffer
+    }
+
+    /// Starts playing `tune` on a single track, to change it while it plays.
+    fn play(instrument: Instrument, tune: &str) -> (Mixer, StopHandle) {
+        let mut rustaphone = Rustaphone::new();
+        rustaphone.add_track(instrument, tune);
+        let mut mixer = Mixer::new();
+        let handle = mixer.play(rustaphone).unwrap();
+        (mixer, handle)
+    }
+
+    /// A sine that holds its full volume for over two seconds.
+    fn sine() -> InstrumentBuilder {
+        Instrument::builder()
+            .with_waveform(Waveform::Sine)
+            .with_sustain(1.0)
+    }
+
+    fn peak(buffer: &[f32]) -> f32 {
+        buffer
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    /// The largest change from one sample to the next.
+    fn largest_step(buffer: &[f32]) -> f32 {
+        buffer
+            .windows(2)
+            .fold(0.0f32, |step, pair| step.max((pair[1] - pair[0]).abs()))
+    }
+
+    /// Upward zero crossings, which count the cycles of a sine.
+    fn crossings(buffer: &[f32]) -> f32 {
+        buffer
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count() as f32
+    }
+
+    #[test]
+    fn chord_voices_respect_polyphony() {
+        let sine = || Instrument::builder().with_waveform(Waveform::Sine).build();
+        let energy = |polyphony| {
+            let mut rustaphone = Rustaphone::new();
+            rustaphone.add_track_with_polyphony(sine(), "(C E G)", polyphony);
+            render(rustaphone, 4096)
+                .iter()
+                .map(|sample| sample * sample)
+                .sum::<f32>()
+        };
+        assert!(energy(3) > energy(1) * 1.5);
+    }
+
+    #[test]
+    fn switches_instruments_mid_track() {
+        let silent = Instrument::builder().with_volume(0.0).build();
+        let loud = |instrument| {
+            let mut rustaphone = Rustaphone::new();
+            rustaphone.add_instrument("lead", Instrument::square());
+            rustaphone.add_track(silent.clone(), instrument);
+            render(rustaphone, 4096).iter().any(|sample| *sample != 0.0)
+        };
+        assert!(!loud("C"));
+        assert!(loud("[inst lead] C"));
+        assert!(!loud("[inst missing] C"));
+
+        let mut rustaphone = Rustaphone::new();
+        rustaphone.add_track(silent.clone(), "[inst bass] C [inst lead] D");
+        let unknown = |name: &str| Warning::UnknownInstrument(name.to_string());
+        assert_eq!(rustaphone.warnings(), [unknown("bass"), unknown("lead")]);
+        rustaphone.add_instrument("lead", Instrument::square());
+        assert_eq!(rustaphone.warnings(), [unknown("bass")]);
+    }
+
+    #[test]
+    fn track_instruments_keep_registered_names() {
+        let mut rustaphone = Rustaphone::new();
+        rustaphone.add_instrument("instrument1", Instrument::square());
+        rustaphone.add_track(Instrument::square(), "[inst instrument1] C");
+        assert_eq!(rustaphone.warnings(), []);
+
+        let song = rustaphone.to_song_string();
+        assert_eq!(song.matches("[instrument instrument1]").count(), 1);
+        assert!(song.contains("[instrument instrument2]"));
+        assert!(Rustaphone::from_song_str(&song).is_ok());
+    }
+
+    #[test]
+    fn written_songs_load_again() {
+        for tempo in [0, 5000] {
+            let mut rustaphone = Rustaphone::new();
+            rustaphone.title("Boss\nfight");
+            rustaphone.tempo(tempo);
+            rustaphone.add_instrument("lead\nsynth", Instrument::square());
+            rustaphone.add_track(Instrument::square(), "C D E");
+
+            let song = rustaphone.to_song_string();
+            assert!(song.contains("title = Boss fight\n"));
+            assert!(song.contains("[instrument lead synth]\n"));
+            let loaded = Rustaphone::from_song_str(&song).unwrap();
+            assert_eq!(loaded.to_song_string(), song);
+        }
+    }
+
+    #[test]
+    fn slurred_notes_keep_the_envelope() {
+        let peak_after_boundary = |tune| {
+            let (mut mixer, _) = play(sine().with_attack(0.2).build(), tune);
+            // an eighth note at 120 bpm lasts 11025 samples
+            peak(&synth(&mut mixer, 11600)[11300..11500])
+        };
+        assert!(peak_after_boundary("8:C_8:E") > peak_after_boundary("8:C 8:E") * 4.0);
+    }
+
+    #[test]
+    fn note_boundaries_are_continuous() {
+        let largest_step_with = |declick| {
+            let mut rustaphone = Rustaphone::new();
+            rustaphone.declick(declick);
+            rustaphone.add_track(sine().build(), "8:C 8:G 8 8:E 8:E");
+            let mut mixer = Mixer::new();
+            let handle = mixer.play(rustaphone).unwrap();
+            let mut buffer = synth(&mut mixer, 50000);
+            // stop halfway through the last note
+            mixer.stop(handle);
+            buffer.extend(synth(&mut mixer, 1000));
+            largest_step(&buffer)
+        };
+        // a sine at these pitches never moves more than about 0.01 per sample
+        assert!(largest_step_with(0.005) < 0.015);
+        assert!(largest_step_with(0.0) > 0.03);
+    }
+
+    #[test]
+    fn parameters_change_smoothly_while_playing() {
+        let (mut mixer, handle) = play(sine().build(), "1:C");
+
+        let before = synth(&mut mixer, 5000);
+        assert!(mixer.set_param(&handle, 0, "volume", 0.05).unwrap());
+        assert!(!mixer.set_param(&handle, 3, "volume", 0.05).unwrap());
+        assert!(mixer.set_param(&handle, 0, "loudness", 0.05).is_err());
+        let after = synth(&mut mixer, 5000);
+
+        assert!(largest_step(&[&before[4999..], &after].concat()) < 0.015);
+        assert!(peak(&after[4000..]) < peak(&before[4000..]) / 5.0);
+    }
+
+    #[test]
+    fn transposes_a_playing_tune() {
+        let (mut mixer, handle) = play(sine().build(), "A A");
+
+        // a quarter note at 120 bpm lasts 22050 samples
+        let first = synth(&mut mixer, 11025);
+        assert!(mixer.transpose(&handle, 12));
+        let rest = synth(&mut mixer, 22050);
+
+        let before = crossings(&first[1000..10000]);
+        let after = crossings(&rest[12000..21000]);
+        assert!((after / before - 2.0).abs() < 0.05, "{before} to {after}");
+    }
+
+    #[test]
+    fn instrument_morphs_reach_the_filters() {
+        let open = sine().build();
+        let closed = sine().with_lpf(0.05).build();
+        let (mut mixer, handle) = play(open.clone(), "1:C5");
+
+        let before = synth(&mut mixer, 5000);
+        let morph = Instrument::lerp(&open, &closed, 1.0);
+        assert!(mixer.set_instrument(&handle, 0, &morph));
+        let after = synth(&mut mixer, 5000);
+
+        assert!(peak(&after[4000..]) < peak(&before[4000..]) / 5.0);
+    }
+
+    #[test]
+    fn lfo_modulates_the_volume() {
+        let instrument = sine()
+            .with_lfo(LfoShape::Square, LfoRate::Hz(10.0))
+            .with_modulation(ModSource::Lfo(0), ModTarget::Volume, -1.0)
+            .build();
+        let mut mixer = Mixer::new();
+        mixer.note_on(&instrument, 69, 100);
+        let buffer = synth(&mut mixer, 4410);
+
+        // silent for the first half of each cycle, twice as loud for the second
+        assert_eq!(peak(&buffer[..2200]), 0.0);
+        assert!(peak(&buffer[2210..]) > 0.05);
+    }
+
+    /// Renders a held A4 on a sustained instrument, with a square LFO that never moves and so
+    /// holds its routes at their full depth.
+    fn held_lfo(waveform: Waveform, target: ModTarget, depth: f32) -> Vec<f32> {
+        let instrument = sine()
+            .with_waveform(waveform)
+            .with_lfo(LfoShape::Square, LfoRate::Hz(0.0))
+            .with_modulation(ModSource::Lfo(0), target, depth)
+            .build();
+        let mut mixer = Mixer::new();
+        mixer.note_on(&instrument, 69, 100);
+        synth(&mut mixer, 44100).split_off(4410)
+    }
+
+    #[test]
+    fn lfo_modulates_the_pitch() {
+        let a4 = crossings(&held_lfo(Waveform::Sine, ModTarget::Pitch, 0.0));
+        let a5 = crossings(&held_lfo(Waveform::Sine, ModTarget::Pitch, 12.0));
+        assert!((a5 / a4 - 2.0).abs() < 0.01, "{a4} to {a5} cycles");
+    }
+
+    #[test]
+    fn lfo_modulates_the_duty() {
+        // the share of the cycle spent high follows the duty cycle
+        let high = |buffer: Vec<f32>| {
+            buffer.iter().filter(|&&sample| sample > 0.0).count() as f32 / buffer.len() as f32
+        };
+        let half = high(held_lfo(Waveform::Square, ModTarget::Duty, 0.0));
+        let narrow = high(held_lfo(Waveform::Square, ModTarget::Duty, -0.25));
+        assert!((half - 0.5).abs() < 0.02, "{half}");
+        assert!((narrow - 0.25).abs() < 0.02, "{narrow}");
+    }
+
+    #[test]
+    fn lfo_rates_follow_the_tempo() {
+        let instrument = sine()
+            .with_lfo(LfoShape::Square, LfoRate::Beats(1.0))
+            .with_modulation(ModSource::Lfo(0), ModTarget::Volume, -1.0)
+            .build();
+
+        // a beat lasts half a second at the 120 beats per minute of live notes
+        let mut mixer = Mixer::new();
+        mixer.note_on(&instrument, 69, 100);
+        let buffer = synth(&mut mixer, 22050);
+        assert_eq!(peak(&buffer[..11000]), 0.0);
+        assert!(peak(&buffer[11050..]) > 0.05);
+
+        // and a quarter of a second at 240
+        let (mut mixer, _) = play(instrument, "[tempo 240] A1");
+        let buffer = synth(&mut mixer, 11025);
+        assert_eq!(peak(&buffer[..5500]), 0.0);
+        assert!(peak(&buffer[5550..]) > 0.05);
+    }
+
+    /// Holds an A4 long enough to settle and returns the tenth of a second after that.
+    fn held(instrument: InstrumentBuilder) -> Vec<f32> {
+        let mut mixer = Mixer::new();
+        mixer.note_on(&instrument.build(), 69, 100);
+        synth(&mut mixer, 8820).split_off(4410)
+    }
+
+    #[test]
+    fn fm_bends_the_waveform() {
+        let plain = held(sine());
+        let bent = held(sine().with_fmindex(0.5));
+        let difference = plain
+            .iter()
+            .zip(&bent)
+            .map(|(a, b)| a - b)
+            .collect::<Vec<_>>();
+        assert!(peak(&difference) > peak(&plain) / 2.0);
+        // the sidebands add higher partials, which move faster
+        assert!(largest_step(&bent) > largest_step(&plain) * 1.5);
+    }
+
+    #[test]
+    fn ring_modulation_follows_the_modulator_sign() {
+        // a 10 Hz modulator, positive for the first half of the tenth of a second and negative
+        // for the second
+        let plain = held(sine());
+        let ringed = held(sine().with_ring(1.0).with_rratio(10.0 / (440.0 * 16.0)));
+        let correlation = |range: std::ops::Range<usize>| {
+            plain[range.clone()]
+                .iter()
+                .zip(&ringed[range])
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+        };
+        assert!(correlation(100..2100) > 0.0);
+        assert!(correlation(2300..4300) < 0.0);
+    }
+
+    #[test]
+    fn live_notes_sustain_until_released() {
+        let instrument = Instrument::builder()
+            .with_waveform(Waveform::Sine)
+            .with_sustain(0.01)
+            .with_decay(0.1)
+            .build();
+        let level = |mixer: &mut Mixer| peak(&synth(mixer, 2000));
+
+        let mut mixer = Mixer::new();
+        let held = mixer.note_on(&instrument, 69, 100);
+        let short = mixer.note_on(&instrument, 76, 100);
+        assert!(mixer.note_off(short));
+        // well past the sustain and decay of the released note
+        for _ in 0..5 {
+            level(&mut mixer);
+        }
+        assert!(!mixer.note_off(short));
+        assert!(level(&mut mixer) > 0.05);
+        assert!(mixer.note_off(held));
+        for _ in 0..5 {
+            level(&mut mixer);
+        }
+        assert_eq!(level(&mut mixer), 0.0);
+        assert!(mixer.is_done());
+    }
+
+    #[test]
+    fn adsr_releases_after_the_note() {
+        let instrument = |adsr| {
+            Instrument::builder()
+                .with_waveform(Waveform::Sine)
+                .with_adsr(adsr)
+                .with_decay(0.05)
+                .with_slevel(0.5)
+                .with_release(0.1)
+                .build()
+        };
+
+        // a whole note at 120 bpm lasts 88200 samples
+        let mut rustaphone = Rustaphone::new();
+        rustaphone.add_track(instrument(true), "1:C");
+        let buffer = render(rustaphone, 90000);
+        let sustain = peak(&buffer[80000..82000]);
+        assert!(sustain > 0.04, "sustain {sustain}");
+        // the release of 1000 samples rings on past the note
+        assert!(peak(&buffer[88300..88500]) > 0.0);
+        assert_eq!(peak(&buffer[89500..]), 0.0);
+
+        let mut rustaphone = Rustaphone::new();
+        rustaphone.add_track(instrument(false), "1:C");
+        assert_eq!(peak(&render(rustaphone, 90000)[80000..82000]), 0.0);
+    }
+
+    #[test]
+    fn params_by_name() {
+        let mut instrument = Instrument::square();
+        assert_eq!(instrument.get("decay"), Some(0.4));
+        instrument.set("decay", 0.7).unwrap();
+        assert_eq!(instrument.get("decay"), Some(0.7));
+
+        // values are clamped to the range, which goes below zero for the signed parameters
+        instrument.set("decay", 2.0).unwrap();
+        instrument.set("slide", -2.0).unwrap();
+        assert_eq!(instrument.get("decay"), Some(1.0));
+        assert_eq!(instrument.get("slide"), Some(-1.0));
+        instrument.set("adsr", 0.8).unwrap();
+        assert_eq!(instrument.get("adsr"), Some(1.0));
+
+        assert_eq!(instrument.get("kazoo"), None);
+        assert!(matches!(
+            instrument.set("kazoo", 1.0),
+            Err(Error::UnknownParam(name)) if name == "kazoo"
+        ));
+    }
+
+    #[test]
+    fn waveform_names_round_trip() {
+        for waveform in [
+            Waveform::Square,
+            Waveform::Sawtooth,
+            Waveform::Sine,
+            Waveform::Noise,
+            Waveform::Triangle,
+            Waveform::NesTriangle,
+            Waveform::Pulse(Duty::Eighth),
+            Waveform::Pulse(Duty::Quarter),
+            Waveform::Pulse(Duty::Half),
+            Waveform::Pulse(Duty::ThreeQuarters),
+            Waveform::PeriodicNoise(NoiseMode::Long),
+            Waveform::PeriodicNoise(NoiseMode::Short),
+        ] {
+            assert_eq!(waveform.to_string().parse::<Waveform>().unwrap(), waveform);
+        }
+        assert!("kazoo".parse::<Waveform>().is_err());
+
+        let wavetable = Waveform::Wavetable(Wavetable::new(vec![0.0, 1.0]));
+        assert!(matches!(
+            wavetable.to_string().parse::<Waveform>(),
+            Err(Error::WaveformData(name)) if name == "wavetable"
+        ));
+        assert!(matches!(
+            "sample".parse::<Waveform>(),
+            Err(Error::WaveformData(_))
+        ));
+    }
+
+    #[test]
+    fn wavetable_interpolation() {
+        let table = Wavetable::new(vec![0.0, 1.0]);
+        assert_eq!(table.sample(0.25), 0.0);
+        let table = table.with_interpolation(Interpolation::Linear);
+        assert_eq!(table.sample(0.25), 0.5);
+        assert_eq!(table.sample(0.75), 0.5);
+
+        let table = Wavetable::from_4bit(&[0, 15]);
+        assert_eq!(table.samples(), &[-1.0, 1.0]);
+    }
+
+    #[test]
+    fn sample_loops() {
+        let sample = Sample::new(vec![0.0, 1.0, 2.0, 3.0], 44100).with_loop(1, 3);
+        assert_eq!(sample.advance(2.5, 1.0), 1.5);
+        assert_eq!(sample.sample(2.5), 1.5);
+        assert_eq!(Sample::new(vec![0.0, 1.0], 44100).sample(5.0), 0.0);
+    }
+
+    #[test]
+    fn duty_snaps_to_nearest() {
+        assert_eq!(Duty::nearest(0.1), Duty::Eighth);
+        assert_eq!(Duty::nearest(0.3), Duty::Quarter);
+        assert_eq!(Duty::nearest(0.9), Duty::ThreeQuarters);
+    }
 }
@@ -0,0 +1,165 @@
+//! Descriptions of the numeric instrument parameters, shared by [`Instrument::get`] and
+//! [`Instrument::set`], the `[name value]` effects in tunes and the song format.
+//!
+//! Values are clamped to the range of each parameter.
+//!
+//! [`Instrument::get`]: super::Instrument::get
+//! [`Instrument::set`]: super::Instrument::set
+
+use super::internal::Params;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    /// An sfxr style setting whose effect is only loosely tied to a physical quantity.
+    Normalized,
+    Hertz,
+    Octaves,
+    /// A plain number without a unit, such as the Q of a filter.
+    Factor,
+    /// Off below 0.5 and on from there.
+    Toggle,
+}
+
+/// A numeric instrument parameter.
+#[derive(Debug, Clone, Copy)]
+pub struct Param {
+    /// Name used by [`Instrument::get`], in tunes as `[name value]` and in song files. Every
+    /// parameter has a tune effect under its name, there is no separate command to look up.
+    ///
+    /// [`Instrument::get`]: super::Instrument::get
+    pub name: &'static str,
+    /// The part of the synthesizer it belongs to, for laying out editors.
+    pub group: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub unit: Unit,
+    pub description: &'static str,
+    get: fn(&Params) -> f32,
+    set: fn(&mut Params, f32),
+}
+
+impl Param {
+    pub(crate) fn get(&self, params: &Params) -> f32 {
+        (self.get)(params)
+    }
+
+    /// Stores `value`, clamped to the range of the parameter.
+    pub(crate) fn set(&self, params: &mut Params, value: f32) {
+        (self.set)(params, value.clamp(self.min, self.max))
+    }
+}
+
+/// Conversion of the fields of [`Params`] to and from the `f32` every parameter is exposed as.
+trait Value {
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Value for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+impl Value for bool {
+    fn to_f32(self) -> f32 {
+        if self {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value >= 0.5
+    }
+}
+
+macro_rules! params {
+    ($($name:ident: $group:literal, $min:literal..=$max:literal, $default:expr, $unit:ident, $description:literal;)*) => {
+        /// Every numeric instrument parameter. The waveform, pan, filter mode and slope have
+        /// their own setters on [`InstrumentBuilder`].
+        ///
+        /// [`InstrumentBuilder`]: super::InstrumentBuilder
+        pub const PARAMS: &[Param] = &[$(Param {
+            name: stringify!($name),
+            group: $group,
+            min: $min,
+            max: $max,
+            default: $default,
+            unit: Unit::$unit,
+            description: $description,
+            get: |params| Value::to_f32(params.$name),
+            set: |params, value| params.$name = Value::from_f32(value),
+        }),*];
+    };
+}
+
+params! {
+    volume: "output", 0.0..=1.0, 0.5, Normalized, "Overall loudness.";
+    punch: "envelope", 0.0..=1.0, 0.0, Normalized, "Extra loudness at the start of the sustain.";
+    attack: "envelope", 0.0..=1.0, 0.0, Normalized, "Time to rise to full volume.";
+    sustain: "envelope", 0.0..=1.0, 0.3, Normalized, "Time to hold full volume, unless adsr is on.";
+    decay: "envelope", 0.0..=1.0, 0.4, Normalized, "Time to fall silent, or to the sustain level with adsr.";
+    adsr: "envelope", 0.0..=1.0, 0.0, Toggle, "Holds the sustain level until the note ends, then releases.";
+    slevel: "envelope", 0.0..=1.0, 0.5, Normalized, "Sustain level of the adsr envelope.";
+    release: "envelope", 0.0..=1.0, 0.2, Normalized, "Time to fall silent after the note ends with adsr.";
+    freq: "pitch", 0.0..=1.0, 0.3, Normalized, "Pitch of sounds that don't come from a tune.";
+    limit: "pitch", 0.0..=1.0, 0.0, Normalized, "Lowest pitch a downward slide may reach before the sound stops.";
+    slide: "pitch", -1.0..=1.0, 0.0, Normalized, "Pitch slide, upwards when positive.";
+    dslide: "pitch", -1.0..=1.0, 0.0, Normalized, "Change of the pitch slide over time.";
+    glide: "pitch", 0.0..=1.0, 0.0, Normalized, "Portamento time from the pitch of the previous note.";
+    legato: "pitch", 0.0..=1.0, 0.0, Toggle, "Changes pitch without restarting the envelope.";
+    square: "square", 0.0..=1.0, 0.0, Normalized, "Duty cycle of the square wave, narrower when higher.";
+    sweep: "square", -1.0..=1.0, 0.0, Normalized, "Change of the duty cycle over time.";
+    vibe: "vibrato", 0.0..=1.0, 0.0, Normalized, "Vibrato depth.";
+    vspeed: "vibrato", 0.0..=1.0, 0.0, Normalized, "Vibrato speed.";
+    vdelay: "vibrato", 0.0..=1.0, 0.0, Normalized, "Unused, kept for sfxr compatibility.";
+    lpf: "filter", 0.0..=1.0, 1.0, Normalized, "Cutoff of the legacy low-pass filter, off at 1.";
+    lsweep: "filter", -1.0..=1.0, 0.0, Normalized, "Change of the low-pass cutoff over time.";
+    resonance: "filter", 0.0..=1.0, 0.0, Normalized, "Resonance of the legacy low-pass filter.";
+    hpf: "filter", 0.0..=1.0, 0.0, Normalized, "Cutoff of the legacy high-pass filter, off at 0.";
+    hsweep: "filter", -1.0..=1.0, 0.0, Normalized, "Change of the high-pass cutoff over time.";
+    cutoff: "filter", 20.0..=20000.0, 1000.0, Hertz, "Cutoff of the multimode filter.";
+    q: "filter", 0.1..=40.0, std::f32::consts::FRAC_1_SQRT_2, Factor, "Resonance of the multimode filter.";
+    fenv: "filter", -10.0..=10.0, 0.0, Octaves, "How far the envelope moves the multimode cutoff.";
+    arp: "arpeggio", -1.0..=1.0, 0.0, Normalized, "Pitch jump, upwards when positive.";
+    aspeed: "arpeggio", 0.0..=1.0, 0.0, Normalized, "How soon the pitch jumps, never at 1.";
+    phase: "phaser", -1.0..=1.0, 0.0, Normalized, "Offset of the phaser.";
+    psweep: "phaser", -1.0..=1.0, 0.0, Normalized, "Change of the phaser offset over time.";
+    repeat: "repeat", 0.0..=1.0, 0.0, Normalized, "Speed at which the pitch and arpeggio start over, never at 0.";
+    fmratio: "fm", 0.0..=1.0, 0.0625, Normalized, "Modulator frequency, 0 to 16 times the note with 1:1 at 0.0625.";
+    fmindex: "fm", 0.0..=1.0, 0.0, Normalized, "Modulation depth, off at 0.";
+    fmattack: "fm", 0.0..=1.0, 0.0, Normalized, "Time for the modulation depth to rise.";
+    fmdecay: "fm", 0.0..=1.0, 0.0, Normalized, "Time for the modulation depth to fall, holding when 0.";
+    ring: "ring", 0.0..=1.0, 0.0, Normalized, "Ring modulation mix, off at 0.";
+    rratio: "ring", 0.0..=1.0, 0.0625, Normalized, "Ring modulator frequency, 0 to 16 times the note.";
+}
+
+/// Looks up a parameter by name.
+pub fn param(name: &str) -> Option<&'static Param> {
+    PARAMS.iter().find(|param| param.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_match_params() {
+        let params = Params::default();
+        for param in PARAMS {
+            assert_eq!(param.get(&params), param.default, "{}", param.name);
+            assert!(
+                (param.min..=param.max).contains(&param.default),
+                "{}",
+                param.name
+            );
+        }
+    }
+}
@@ -0,0 +1,558 @@
+//! Reading and writing the song text format, see [`Rustaphone::from_song_str`].
+//!
+//! [`Rustaphone::from_song_str`]: super::Rustaphone::from_song_str
+
+use std::fmt::Write;
+
+use super::{
+    internal::Params,
+    params::{self, Unit, PARAMS},
+    Error, FilterMode, FilterSlope, Instrument, Interpolation, LfoRate, LfoShape, ModSource,
+    ModTarget, Sample, Waveform, Wavetable,
+};
+
+pub(crate) struct Track {
+    pub name: String,
+    pub instrument: String,
+    pub polyphony: usize,
+    pub tune: String,
+}
+
+#[derive(Default)]
+pub(crate) struct Song {
+    pub title: Option<String>,
+    pub tempo: Option<i32>,
+    pub instruments: Vec<(String, Instrument)>,
+    pub tracks: Vec<Track>,
+}
+
+fn filter_name(filter: FilterMode) -> &'static str {
+    match filter {
+        FilterMode::Legacy => "legacy",
+        FilterMode::LowPass => "lowpass",
+        FilterMode::HighPass => "highpass",
+        FilterMode::BandPass => "bandpass",
+        FilterMode::Notch => "notch",
+    }
+}
+
+fn interpolation_name(interpolation: Interpolation) -> &'static str {
+    match interpolation {
+        Interpolation::None => "none",
+        Interpolation::Linear => "linear",
+        Interpolation::Cubic => "cubic",
+    }
+}
+
+fn lfo_shape_name(shape: LfoShape) -> &'static str {
+    match shape {
+        LfoShape::Sine => "sine",
+        LfoShape::Triangle => "triangle",
+        LfoShape::Square => "square",
+        LfoShape::SampleAndHold => "sampleandhold",
+    }
+}
+
+fn mod_target_name(target: ModTarget) -> &'static str {
+    match target {
+        ModTarget::Pitch => "pitch",
+        ModTarget::Volume => "volume",
+        ModTarget::Cutoff => "cutoff",
+        ModTarget::Duty => "duty",
+        ModTarget::Phaser => "phaser",
+    }
+}
+
+/// Joins the lines of a title or name, which would otherwise start new settings.
+fn one_line(value: &str) -> String {
+    value.lines().map(str::trim).collect::<Vec<_>>().join(" ")
+}
+
+fn values(values: &[f32]) -> String {
+    let values: Vec<_> = values.iter().map(f32::to_string).collect();
+    values.join(", ")
+}
+
+/// Waveform pieces that may come in any order, put together at the end of the section.
+#[derive(Default)]
+struct Oscillator {
+    waveform: Option<String>,
+    wavetable: Option<Vec<f32>>,
+    interpolation: Interpolation,
+    sample: Option<Vec<f32>>,
+    sample_rate: Option<u32>,
+    root: Option<f32>,
+    loop_points: Option<(usize, usize)>,
+}
+
+impl Oscillator {
+    fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "waveform" => self.waveform = Some(value.to_string()),
+            "wavetable" => self.wavetable = Some(parse_values(value)?),
+            "interpolation" => {
+                self.interpolation = match value {
+                    "none" => Interpolation::None,
+                    "linear" => Interpolation::Linear,
+                    "cubic" => Interpolation::Cubic,
+                    _ => return Err(format!("unknown interpolation: {value}")),
+                }
+            }
+            "sample" => self.sample = Some(parse_values(value)?),
+            "samplerate" => self.sample_rate = Some(parse(value)?),
+            "root" => self.root = Some(parse(value)?),
+            "loop" => {
+                let (start, end) = value
+                    .split_once(' ')
+                    .ok_or_else(|| format!("expected a start and end frame: {value}"))?;
+                self.loop_points = Some((parse(start)?, parse(end.trim())?));
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn build(self) -> Result<Option<Waveform>, String> {
+        let Some(name) = self.waveform else {
+            return Ok(None);
+        };
+
+        let waveform = match name.as_str() {
+            "wavetable" => {
+                let samples = self.wavetable.ok_or("wavetable without samples")?;
+                Waveform::Wavetable(Wavetable::new(samples).with_interpolation(self.interpolation))
+            }
+            "sample" => {
+                let data = self.sample.ok_or("sample without data")?;
+                let rate = self.sample_rate.ok_or("sample without samplerate")?;
+                let mut sample = Sample::new(data, rate);
+                if let Some(root) = self.root {
+                    sample = sample.with_root(root);
+                }
+                if let Some((start, end)) = self.loop_points {
+                    sample = sample.with_loop(start, end);
+                }
+                Waveform::Sample(sample)
+            }
+            name => name.parse().map_err(|err: Error| err.to_string())?,
+        };
+
+        Ok(Some(waveform))
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value: {value}"))
+}
+
+fn parse_values(value: &str) -> Result<Vec<f32>, String> {
+    value.split(',').map(|value| parse(value.trim())).collect()
+}
+
+fn set_param(params: &mut Params, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "pan" => params.pan = parse(value)?,
+        "filter" => {
+            params.filter = match value {
+                "legacy" => FilterMode::Legacy,
+                "lowpass" => FilterMode::LowPass,
+                "highpass" => FilterMode::HighPass,
+                "bandpass" => FilterMode::BandPass,
+                "notch" => FilterMode::Notch,
+                _ => return Err(format!("unknown filter: {value}")),
+            }
+        }
+        "slope" => {
+            params.slope = match value {
+                "12" => FilterSlope::Db12,
+                "24" => FilterSlope::Db24,
+                _ => return Err(format!("slope must be 12 or 24: {value}")),
+            }
+        }
+        // `lfo = sine 5 hz` or `lfo = square 2 beats`
+        "lfo" => {
+            let [shape, rate, unit] = value.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(format!("expected a shape, rate and unit: {value}"));
+            };
+            let shape = match shape {
+                "sine" => LfoShape::Sine,
+                "triangle" => LfoShape::Triangle,
+                "square" => LfoShape::Square,
+                "sampleandhold" => LfoShape::SampleAndHold,
+                _ => return Err(format!("unknown lfo shape: {shape}")),
+            };
+            let rate = match unit {
+                "hz" => LfoRate::Hz(parse(rate)?),
+                "beats" => LfoRate::Beats(parse(rate)?),
+                _ => return Err(format!("lfo rate must be in hz or beats: {unit}")),
+            };
+            params.lfos.push((shape, rate));
+        }
+        // `modulation = lfo1 pitch 0.5` or `modulation = envelope cutoff 2`
+        "modulation" => {
+            let [source, target, depth] = value.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(format!("expected a source, target and depth: {value}"));
+            };
+            let source = match source.strip_prefix("lfo") {
+                Some(n) => match parse::<usize>(n)? {
+                    0 => return Err(format!("lfos count from 1: {source}")),
+                    n => ModSource::Lfo(n - 1),
+                },
+                None if source == "envelope" => ModSource::Envelope,
+                None => return Err(format!("unknown modulation source: {source}")),
+            };
+            let target = match target {
+                "pitch" => ModTarget::Pitch,
+                "volume" => ModTarget::Volume,
+                "cutoff" => ModTarget::Cutoff,
+                "duty" => ModTarget::Duty,
+                "phaser" => ModTarget::Phaser,
+                _ => return Err(format!("unknown modulation target: {target}")),
+            };
+            params.modulation.push((source, target, parse(depth)?));
+        }
+        _ => {
+            let param =
+                params::param(key).ok_or_else(|| format!("unknown instrument setting: {key}"))?;
+            let value = match param.unit {
+                // toggles read as true or false rather than numbers
+                Unit::Toggle if parse::<bool>(value)? => 1.0,
+                Unit::Toggle => 0.0,
+                _ => parse(value)?,
+            };
+            param.set(params, value);
+        }
+    }
+
+    Ok(())
+}
+
+enum Section {
+    Song,
+    Instrument(String, Box<Params>, Oscillator),
+    Track(Track),
+}
+
+impl Section {
+    fn finish(self, song: &mut Song) -> Result<(), String> {
+        match self {
+            Section::Song => {}
+            Section::Instrument(name, mut params, oscillator) => {
+                if let Some(waveform) = oscillator.build()? {
+                    params.r#type = waveform;
+                }
+                song.instruments
+                    .push((name, Instrument { params: *params }));
+            }
+            Section::Track(track) => song.tracks.push(track),
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn parse_song(source: &str) -> Result<Song, Error> {
+    let mut song = Song::default();
+    let mut section = Section::Song;
+    let mut start = 0;
+
+    for (number, line) in source.lines().enumerate() {
+        let invalid = |reason: String| Error::InvalidSong {
+            line: number + 1,
+            reason,
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let next = match header.split_once(' ') {
+                Some(("instrument", name)) => Section::Instrument(
+                    name.trim().to_string(),
+                    Box::default(),
+                    Oscillator::default(),
+                ),
+                Some(("track", name)) => Section::Track(Track {
+                    name: name.trim().to_string(),
+                    instrument: String::new(),
+                    polyphony: super::internal::DEFAULT_POLYPHONY,
+                    tune: String::new(),
+                }),
+                _ => return Err(invalid(format!("unknown section: {header}"))),
+            };
+            std::mem::replace(&mut section, next)
+                .finish(&mut song)
+                .map_err(|reason| Error::InvalidSong {
+                    line: start,
+                    reason,
+                })?;
+            // tracks look instruments up by name, so it has to pick out one of them
+            if let Section::Instrument(name, ..) = &section {
+                if song.instruments.iter().any(|(n, _)| n == name) {
+                    return Err(invalid(format!("duplicate instrument: {name}")));
+                }
+            }
+            start = number + 1;
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(invalid(format!("expected `key = value`: {line}")));
+        };
+        let (key, value) = (key.trim(), value.trim());
+
+        match &mut section {
+            Section::Song => match key {
+                "title" => song.title = Some(value.to_string()),
+                "tempo" => {
+                    let tempo = parse(value).map_err(invalid)?;
+                    // the same range as the `[tempo]` directive
+                    if !(1..=1000).contains(&tempo) {
+                        return Err(invalid(format!("tempo must be from 1 to 1000: {value}")));
+                    }
+                    song.tempo = Some(tempo);
+                }
+                _ => return Err(invalid(format!("unknown song setting: {key}"))),
+            },
+            Section::Instrument(_, params, oscillator) => {
+                if !oscillator.set(key, value).map_err(invalid)? {
+                    set_param(params, key, value).map_err(invalid)?;
+                }
+            }
+            Section::Track(track) => match key {
+                "instrument" => track.instrument = value.to_string(),
+                "polyphony" => track.polyphony = parse(value).map_err(invalid)?,
+                "tune" => {
+                    if !track.tune.is_empty() {
+                        track.tune.push('\n');
+                    }
+                    track.tune.push_str(value);
+                }
+                _ => return Err(invalid(format!("unknown track setting: {key}"))),
+            },
+        }
+    }
+    section
+        .finish(&mut song)
+        .map_err(|reason| Error::InvalidSong {
+            line: start,
+            reason,
+        })?;
+
+    for track in &song.tracks {
+        if !song
+            .instruments
+            .iter()
+            .any(|(name, _)| *name == track.instrument)
+        {
+            return Err(Error::InvalidSong {
+                line: 0,
+                reason: format!(
+                    "track {} uses unknown instrument: {}",
+                    track.name, track.instrument
+                ),
+            });
+        }
+    }
+
+    Ok(song)
+}
+
+fn write_instrument(out: &mut String, name: &str, instrument: &Instrument) {
+    let params = &instrument.params;
+    let defaults = Params::default();
+
+    writeln!(out, "[instrument {name}]").unwrap();
+    writeln!(out, "waveform = {}", params.r#type).unwrap();
+    match &params.r#type {
+        Waveform::Wavetable(table) => {
+            writeln!(out, "wavetable = {}", values(table.samples())).unwrap();
+            writeln!(
+                out,
+                "interpolation = {}",
+                interpolation_name(table.interpolation)
+            )
+            .unwrap();
+        }
+        Waveform::Sample(sample) => {
+            writeln!(out, "samplerate = {}", sample.sample_rate).unwrap();
+            writeln!(out, "root = {}", sample.root).unwrap();
+            if let Some((start, end)) = sample.loop_points {
+                writeln!(out, "loop = {start} {end}").unwrap();
+            }
+            writeln!(out, "sample = {}", values(sample.data())).unwrap();
+        }
+        _ => {}
+    }
+    if params.pan != defaults.pan {
+        writeln!(out, "pan = {}", params.pan).unwrap();
+    }
+    if params.filter != defaults.filter {
+        writeln!(out, "filter = {}", filter_name(params.filter)).unwrap();
+    }
+    if params.slope != defaults.slope {
+        writeln!(out, "slope = 24").unwrap();
+    }
+    for &(shape, rate) in &params.lfos {
+        let (rate, unit) = match rate {
+            LfoRate::Hz(hz) => (hz, "hz"),
+            LfoRate::Beats(beats) => (beats, "beats"),
+        };
+        writeln!(out, "lfo = {} {rate} {unit}", lfo_shape_name(shape)).unwrap();
+    }
+    for &(source, target, depth) in &params.modulation {
+        let source = match source {
+            ModSource::Lfo(n) => format!("lfo{}", n + 1),
+            ModSource::Envelope => "envelope".to_string(),
+        };
+        let target = mod_target_name(target);
+        writeln!(out, "modulation = {source} {target} {depth}").unwrap();
+    }
+
+    for param in PARAMS {
+        let value = param.get(params);
+        // only what differs from the defaults, to keep files short
+        if value != param.get(&defaults) {
+            match param.unit {
+                Unit::Toggle => writeln!(out, "{} = {}", param.name, value >= 0.5).unwrap(),
+                _ => writeln!(out, "{} = {value}", param.name).unwrap(),
+            }
+        }
+    }
+}
+
+pub(crate) fn write_song(song: &Song) -> String {
+    let mut out = String::new();
+
+    if let Some(title) = &song.title {
+        writeln!(out, "title = {}", one_line(title)).unwrap();
+    }
+    if let Some(tempo) = song.tempo {
+        writeln!(out, "tempo = {tempo}").unwrap();
+    }
+
+    for (name, instrument) in &song.instruments {
+        out.push('\n');
+        write_instrument(&mut out, &one_line(name), instrument);
+    }
+
+    for track in &song.tracks {
+        out.push('\n');
+        writeln!(out, "[track {}]", one_line(&track.name)).unwrap();
+        writeln!(out, "instrument = {}", one_line(&track.instrument)).unwrap();
+        writeln!(out, "polyphony = {}", track.polyphony).unwrap();
+        for line in track.tune.lines() {
+            writeln!(out, "tune = {}", line.trim()).unwrap();
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "# boss fight
+title = Boss fight
+tempo = 160
+
+[instrument lead]
+waveform = pulse25
+decay = 0.2
+filter = lowpass
+cutoff = 2400
+lfo = triangle 6 hz
+modulation = lfo1 pitch 0.3
+modulation = envelope cutoff 2
+
+[instrument pad]
+waveform = wavetable
+wavetable = 0, 0.5, 1, 0.5
+interpolation = cubic
+
+[track melody]
+instrument = lead
+tune = [key D] C D E F |
+tune = G A B C |
+
+[track chords]
+instrument = pad
+polyphony = 3
+tune = (C E G) (D F A)
+";
+
+    #[test]
+    fn round_trip() {
+        let song = parse_song(SONG).unwrap();
+        assert_eq!(song.title.as_deref(), Some("Boss fight"));
+        assert_eq!(song.tempo, Some(160));
+        assert_eq!(song.instruments.len(), 2);
+        assert_eq!(song.instruments[0].1.params.cutoff, 2400.0);
+        assert_eq!(song.instruments[0].1.params.modulation.len(), 2);
+        assert_eq!(song.tracks[0].tune, "[key D] C D E F |\nG A B C |");
+        assert_eq!(song.tracks[1].polyphony, 3);
+
+        let written = write_song(&song);
+        assert_eq!(write_song(&parse_song(&written).unwrap()), written);
+    }
+
+    #[test]
+    fn reports_the_line() {
+        let Err(Error::InvalidSong { line, .. }) =
+            parse_song("tempo = 100\n\n[track a]\nbogus = 1")
+        else {
+            panic!("expected an error");
+        };
+        assert_eq!(line, 4);
+    }
+
+    #[test]
+    fn rejects_tempos_out_of_range() {
+        for tempo in ["0", "-20", "1001"] {
+            let Err(Error::InvalidSong { line, .. }) =
+                parse_song(&format!("title = a\ntempo = {tempo}"))
+            else {
+                panic!("expected an error for tempo {tempo}");
+            };
+            assert_eq!(line, 2);
+        }
+        assert_eq!(parse_song("tempo = 1000").unwrap().tempo, Some(1000));
+    }
+
+    #[test]
+    fn rejects_duplicate_instruments() {
+        let Err(Error::InvalidSong { line, reason }) =
+            parse_song("[instrument a]\nvolume = 0.2\n\n[instrument a]\nvolume = 0.8")
+        else {
+            panic!("expected an error");
+        };
+        assert_eq!((line, reason.as_str()), (4, "duplicate instrument: a"));
+    }
+
+    #[test]
+    fn any_number_of_lfos() {
+        let song = parse_song(
+            "[instrument a]\n\
+             lfo = sine 1 hz\nlfo = sine 2 hz\nlfo = sine 3 hz\nlfo = sine 4 hz\n\
+             lfo = square 1 beats\nmodulation = lfo5 volume -1\n",
+        )
+        .unwrap();
+        let params = &song.instruments[0].1.params;
+        assert_eq!(params.lfos.len(), 5);
+        assert_eq!(params.modulation[0].0, ModSource::Lfo(4));
+    }
+
+    #[test]
+    fn clamps_values_to_the_param_range() {
+        let song = parse_song("[instrument a]\nvolume = 3\nslide = -0.5\ncutoff = 5\n").unwrap();
+        let params = &song.instruments[0].1.params;
+        assert_eq!(
+            (params.volume, params.slide, params.cutoff),
+            (1.0, -0.5, 20.0)
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! Setters and getters in real units, converting to and from the 0–1 parameters.
+//!
+//! The conversions assume playback at 44.1 kHz, which is what the sfxr parameters were tuned
+//! for. At other sample rates times and frequencies scale with the rate.
+
+use super::{internal, Instrument, InstrumentBuilder};
+
+const NOMINAL_RATE: f32 = 44100.0;
+/// The oscillator and legacy filters run 8 steps per output sample.
+const OVERSAMPLED_RATE: f32 = NOMINAL_RATE * 8.0;
+
+/// Envelope stages and glides last `param² * 100000` samples.
+fn secs_to_param(secs: f32) -> f32 {
+    f32::sqrt(secs.max(0.0) * NOMINAL_RATE / 100000.0)
+}
+
+fn param_to_secs(param: f32) -> f32 {
+    param * param * 100000.0 / NOMINAL_RATE
+}
+
+/// A period of `100 / (freq² + 0.001)` oversampled steps.
+fn hz_to_freq(hz: f32) -> f32 {
+    f32::sqrt((hz * 100.0 / OVERSAMPLED_RATE - 0.001).max(0.0))
+}
+
+fn freq_to_hz(freq: f32) -> f32 {
+    OVERSAMPLED_RATE * (freq * freq + 0.001) / 100.0
+}
+
+/// Cutoff of the one-pole high-pass filter whose per-step coefficient is `coefficient`.
+fn coefficient_to_hz(coefficient: f32) -> f32 {
+    coefficient * OVERSAMPLED_RATE / std::f32::consts::TAU
+}
+
+fn hz_to_coefficient(hz: f32) -> f32 {
+    (hz * std::f32::consts::TAU / OVERSAMPLED_RATE).clamp(0.0, 0.1)
+}
+
+/// Gain of the legacy low-pass filter at `hz`, from the transfer function of its recurrence
+/// `v += (x - y) * w; v -= v * d; y += v` and the averaging of 8 steps into each sample:
+///
+/// ```text
+/// H(z) = (1 - d) w / ((1 - z⁻¹) (1 - (1 - d) z⁻¹) + (1 - d) w z⁻¹)
+/// ```
+fn lowpass_gain(lpf: f32, resonance: f32, hz: f32) -> f32 {
+    let (w, d) = internal::lowpass(lpf, resonance);
+    let (w, d) = (w.min(0.1) as f64, d as f64);
+    let theta = std::f64::consts::TAU * hz as f64 / OVERSAMPLED_RATE as f64;
+    let (sin, cos) = theta.sin_cos();
+
+    let k = (1.0 - d) * w;
+    // (1 - z⁻¹) (1 - (1 - d) z⁻¹) with z⁻¹ = cos - i sin
+    let (are, aim) = (1.0 - cos, sin);
+    let (bre, bim) = (1.0 - (1.0 - d) * cos, (1.0 - d) * sin);
+    let re = are * bre - aim * bim + k * cos;
+    let im = are * bim + aim * bre - k * sin;
+    let average = (4.0 * theta).sin() / (8.0 * (theta / 2.0).sin());
+
+    (k / re.hypot(im) * average.abs()) as f32
+}
+
+/// The -3 dB point of the legacy low-pass filter, or infinity if it lets everything up to half
+/// the sample rate through.
+fn lpf_to_hz(lpf: f32, resonance: f32) -> f32 {
+    let passes = |hz| lowpass_gain(lpf, resonance, hz) >= std::f32::consts::FRAC_1_SQRT_2;
+    if lpf >= 1.0 {
+        return f32::INFINITY;
+    }
+
+    // resonance lifts the gain before it falls, so step up to the first frequency below -3 dB
+    let mut hz = 1.0;
+    while passes(hz) {
+        hz *= 1.02;
+        if hz > NOMINAL_RATE / 2.0 {
+            return f32::INFINITY;
+        }
+    }
+    let (mut low, mut high) = (hz / 1.02, hz);
+    for _ in 0..30 {
+        let middle = (low + high) / 2.0;
+        if passes(middle) {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    (low + high) / 2.0
+}
+
+/// The damping grows with the cutoff, so past some point a higher `lpf` lowers the cutoff
+/// again. Only the part below that point is used, anything higher turns the filter off.
+fn hz_to_lpf(hz: f32, resonance: f32) -> f32 {
+    let (top, highest) = (0..100)
+        .map(|i| i as f32 / 100.0)
+        .map(|lpf| (lpf, lpf_to_hz(lpf, resonance)))
+        .fold(
+            (0.0, 0.0),
+            |top, (lpf, cutoff)| {
+                if cutoff > top.1 {
+                    (lpf, cutoff)
+                } else {
+                    top
+                }
+            },
+        );
+    if hz >= highest {
+        return 1.0;
+    }
+
+    let (mut low, mut high) = (0.0, top);
+    for _ in 0..30 {
+        let middle = (low + high) / 2.0;
+        if lpf_to_hz(middle, resonance) < hz {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    (low + high) / 2.0
+}
+
+/// The vibrato phase advances by `vspeed² * 0.01` radians per sample.
+fn vibrato_hz_to_param(hz: f32) -> f32 {
+    f32::sqrt(hz.max(0.0) * std::f32::consts::TAU / (0.01 * NOMINAL_RATE))
+}
+
+fn vibrato_param_to_hz(vspeed: f32) -> f32 {
+    vspeed * vspeed * 0.01 * NOMINAL_RATE / std::f32::consts::TAU
+}
+
+/// The period is multiplied by `1 - slide³ * 0.01` every sample, so a positive slide rises.
+fn slide_to_semitones_per_sec(slide: f32) -> f32 {
+    let factor = 1.0 - slide.powi(3) as f64 * 0.01;
+    (-12.0 * factor.log2() * NOMINAL_RATE as f64) as f32
+}
+
+fn semitones_per_sec_to_slide(semitones: f32) -> f32 {
+    let factor = f64::powf(2.0, -semitones as f64 / (12.0 * NOMINAL_RATE as f64));
+    (((1.0 - factor) / 0.01) as f32).cbrt().clamp(-1.0, 1.0)
+}
+
+/// The arpeggio multiplies the period by `1 - arp² * 0.9` going up, `1 + arp² * 10` down.
+fn arp_to_semitones(arp: f32) -> f32 {
+    let factor = if arp >= 0.0 {
+        1.0 - arp * arp * 0.9
+    } else {
+        1.0 + arp * arp * 10.0
+    };
+    -12.0 * factor.log2()
+}
+
+fn semitones_to_arp(semitones: f32) -> f32 {
+    let factor = f32::powf(2.0, -semitones / 12.0);
+    if factor <= 1.0 {
+        f32::sqrt(((1.0 - factor) / 0.9).min(1.0))
+    } else {
+        -f32::sqrt(((factor - 1.0) / 10.0).min(1.0))
+    }
+}
+
+/// The arpeggio jumps after `(1 - aspeed)² * 20000 + 32` samples.
+fn aspeed_to_secs(aspeed: f32) -> f32 {
+    ((1.0 - aspeed).powi(2) * 20000.0 + 32.0) / NOMINAL_RATE
+}
+
+fn secs_to_aspeed(secs: f32) -> f32 {
+    let samples = (secs * NOMINAL_RATE - 32.0).clamp(0.0, 20000.0);
+    1.0 - f32::sqrt(samples / 20000.0)
+}
+
+impl InstrumentBuilder {
+    pub fn with_attack_secs(self, secs: f32) -> InstrumentBuilder {
+        self.with_attack(secs_to_param(secs))
+    }
+
+    pub fn with_sustain_secs(self, secs: f32) -> InstrumentBuilder {
+        self.with_sustain(secs_to_param(secs))
+    }
+
+    pub fn with_decay_secs(self, secs: f32) -> InstrumentBuilder {
+        self.with_decay(secs_to_param(secs))
+    }
+
+    pub fn with_release_secs(self, secs: f32) -> InstrumentBuilder {
+        self.with_release(secs_to_param(secs))
+    }
+
+    pub fn with_glide_secs(self, secs: f32) -> InstrumentBuilder {
+        self.with_glide(secs_to_param(secs))
+    }
+
+    /// Pitch of notes that don't set their own, such as sound effects.
+    pub fn with_freq_hz(self, hz: f32) -> InstrumentBuilder {
+        self.with_freq(hz_to_freq(hz))
+    }
+
+    /// The -3 dB point of the legacy low-pass filter. It depends on the resonance as well, so
+    /// set that first. Without resonance the filter reaches up to about 8.9 kHz, cutoffs higher
+    /// than it can reach turn it off.
+    pub fn with_lpf_hz(self, hz: f32) -> InstrumentBuilder {
+        let lpf = hz_to_lpf(hz, self.params.resonance);
+        self.with_lpf(lpf)
+    }
+
+    /// Approximate cutoff of the legacy high-pass filter.
+    pub fn with_hpf_hz(self, hz: f32) -> InstrumentBuilder {
+        self.with_hpf(f32::sqrt(hz_to_coefficient(hz) / 0.1))
+    }
+
+    pub fn with_vibrato_hz(self, hz: f32) -> InstrumentBuilder {
+        self.with_vspeed(vibrato_hz_to_param(hz))
+    }
+
+    /// Vibrato depth, as the distance from the centre pitch.
+    pub fn with_vibrato_semitones(self, semitones: f32) -> InstrumentBuilder {
+        self.with_vibe((f32::powf(2.0, semitones / 12.0) - 1.0) / 0.5)
+    }
+
+    /// Pitch slide, positive values slide up.
+    pub fn with_slide_semitones_per_sec(self, semitones: f32) -> InstrumentBuilder {
+        self.with_slide(semitones_per_sec_to_slide(semitones))
+    }
+
+    /// Pitch jump of the arpeggio, from about an octave and a half down to four octaves up.
+    pub fn with_arp_semitones(self, semitones: f32) -> InstrumentBuilder {
+        self.with_arp(semitones_to_arp(semitones))
+    }
+
+    /// Time before the arpeggio jumps, from 0.7 ms to 0.45 s.
+    pub fn with_arp_secs(self, secs: f32) -> InstrumentBuilder {
+        self.with_aspeed(secs_to_aspeed(secs))
+    }
+}
+
+impl Instrument {
+    pub fn attack_secs(&self) -> f32 {
+        param_to_secs(self.params.attack)
+    }
+
+    pub fn sustain_secs(&self) -> f32 {
+        param_to_secs(self.params.sustain)
+    }
+
+    pub fn decay_secs(&self) -> f32 {
+        param_to_secs(self.params.decay)
+    }
+
+    pub fn release_secs(&self) -> f32 {
+        param_to_secs(self.params.release)
+    }
+
+    pub fn glide_secs(&self) -> f32 {
+        param_to_secs(self.params.glide)
+    }
+
+    pub fn freq_hz(&self) -> f32 {
+        freq_to_hz(self.params.freq)
+    }
+
+    /// The -3 dB point of the legacy low-pass filter, infinite when it is off.
+    pub fn lpf_hz(&self) -> f32 {
+        lpf_to_hz(self.params.lpf, self.params.resonance)
+    }
+
+    pub fn hpf_hz(&self) -> f32 {
+        coefficient_to_hz(self.params.hpf.powi(2) * 0.1)
+    }
+
+    pub fn vibrato_hz(&self) -> f32 {
+        vibrato_param_to_hz(self.params.vspeed)
+    }
+
+    pub fn vibrato_semitones(&self) -> f32 {
+        12.0 * f32::log2(1.0 + self.params.vibe * 0.5)
+    }
+
+    pub fn slide_semitones_per_sec(&self) -> f32 {
+        slide_to_semitones_per_sec(self.params.slide)
+    }
+
+    pub fn arp_semitones(&self) -> f32 {
+        arp_to_semitones(self.params.arp)
+    }
+
+    pub fn arp_secs(&self) -> f32 {
+        aspeed_to_secs(self.params.aspeed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LfoRate, LfoShape, Mixer, ModSource, ModTarget, Rustaphone, Waveform};
+
+    #[test]
+    fn conversions_round_trip() {
+        let instrument = Instrument::builder()
+            .with_attack_secs(0.05)
+            .with_freq_hz(440.0)
+            .with_lpf_hz(2000.0)
+            .with_hpf_hz(100.0)
+            .with_vibrato_hz(6.0)
+            .with_vibrato_semitones(0.5)
+            .with_slide_semitones_per_sec(-24.0)
+            .with_arp_semitones(7.0)
+            .with_arp_secs(0.1)
+            .build();
+
+        let close = |a: f32, b: f32| (a - b).abs() < b.abs() * 1e-3 + 1e-4;
+        assert!(close(instrument.attack_secs(), 0.05));
+        assert!(close(instrument.freq_hz(), 440.0));
+        assert!(close(instrument.lpf_hz(), 2000.0));
+        assert!(close(instrument.hpf_hz(), 100.0));
+        assert!(close(instrument.vibrato_hz(), 6.0));
+        assert!(close(instrument.vibrato_semitones(), 0.5));
+        assert!(close(instrument.slide_semitones_per_sec(), -24.0));
+        assert!(close(instrument.arp_semitones(), 7.0));
+        assert!(close(instrument.arp_secs(), 0.1));
+    }
+
+    /// Level of a held note, after the filters have settled.
+    fn rms(instrument: Instrument) -> f32 {
+        let mut rustaphone = Rustaphone::new();
+        rustaphone.add_track(instrument, "");
+        let mut mixer = Mixer::new();
+        mixer.play(rustaphone);
+        let mut buffer = vec![0.0; 20000];
+        mixer.synth(44100, &mut buffer);
+        let held = &buffer[10000..];
+        (held.iter().map(|sample| sample * sample).sum::<f32>() / held.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn lpf_cutoff_is_the_measured_3db_point() {
+        for (hz, resonance) in [(500.0, 0.0), (2000.0, 0.0), (4000.0, 0.5)] {
+            let sine = || {
+                Instrument::builder()
+                    .with_waveform(Waveform::Sine)
+                    .with_freq_hz(hz)
+                    .with_sustain(1.0)
+                    .with_resonance(resonance)
+            };
+            let gain = rms(sine().with_lpf_hz(hz).build()) / rms(sine().build());
+            assert!(
+                (gain - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.02,
+                "{hz} Hz: {gain}"
+            );
+        }
+
+        let off = Instrument::builder().with_lpf_hz(12000.0).build();
+        assert_eq!(off.lpf_hz(), f32::INFINITY);
+    }
+
+    #[test]
+    fn cutoff_modulation_moves_the_lpf_by_octaves() {
+        for (hz, resonance) in [(500.0, 0.0), (1000.0, 0.5)] {
+            let sine = || {
+                Instrument::builder()
+                    .with_waveform(Waveform::Sine)
+                    .with_freq_hz(hz * 2.0)
+                    .with_sustain(1.0)
+                    .with_resonance(resonance)
+            };
+            // a square LFO that never moves holds the cutoff one octave up
+            let raised = sine()
+                .with_lpf_hz(hz)
+                .with_lfo(LfoShape::Square, LfoRate::Hz(0.0))
+                .with_modulation(ModSource::Lfo(0), ModTarget::Cutoff, 1.0)
+                .build();
+            let gain = rms(raised) / rms(sine().build());
+            assert!(
+                (gain - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.03,
+                "{hz} Hz: {gain}"
+            );
+        }
+    }
+
+    #[test]
+    fn middle_a_matches_the_note_table() {
+        // A4 in the tune notation uses a frequency parameter of 0.353
+        assert!((freq_to_hz(0.353) - 440.0).abs() < 5.0);
+    }
+}
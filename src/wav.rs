@@ -0,0 +1,151 @@
+use super::Error;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+struct Format {
+    tag: u16,
+    channels: u16,
+    sample_rate: u32,
+    bits: u16,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn format(chunk: &[u8]) -> Result<Format, Error> {
+    let (Some(mut tag), Some(channels), Some(sample_rate), Some(bits)) = (
+        u16_at(chunk, 0),
+        u16_at(chunk, 2),
+        u32_at(chunk, 4),
+        u16_at(chunk, 14),
+    ) else {
+        return Err(Error::InvalidWav("truncated fmt chunk"));
+    };
+
+    if tag == WAVE_FORMAT_EXTENSIBLE {
+        // the actual format tag is the start of the sub-format GUID
+        tag = u16_at(chunk, 24).ok_or(Error::InvalidWav("truncated fmt chunk"))?;
+    }
+
+    if channels == 0 {
+        return Err(Error::InvalidWav("no channels"));
+    }
+
+    Ok(Format {
+        tag,
+        channels,
+        sample_rate,
+        bits,
+    })
+}
+
+fn sample(format: &Format, bytes: &[u8]) -> Result<f32, Error> {
+    let sample = match (format.tag, format.bits) {
+        (WAVE_FORMAT_PCM, 8) => (bytes[0] as f32 - 128.0) / 128.0,
+        (WAVE_FORMAT_PCM, 16) => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+        (WAVE_FORMAT_PCM, 24) => {
+            i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2147483648.0
+        }
+        (WAVE_FORMAT_PCM, 32) => {
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2147483648.0
+        }
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => {
+            f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        }
+        (WAVE_FORMAT_IEEE_FLOAT, 64) => f64::from_le_bytes(bytes[..8].try_into().unwrap()) as f32,
+        _ => return Err(Error::InvalidWav("unsupported sample format")),
+    };
+
+    Ok(sample)
+}
+
+/// Decodes a RIFF/WAVE file into its sample rate and mono samples. Multi-channel files are
+/// mixed down by averaging the channels.
+pub(crate) fn decode(bytes: &[u8]) -> Result<(u32, Vec<f32>), Error> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(Error::InvalidWav("not a RIFF/WAVE file"));
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut at = 12;
+    while at + 8 <= bytes.len() {
+        let id = &bytes[at..at + 4];
+        let size = u32_at(bytes, at + 4).unwrap() as usize;
+        let chunk = &bytes[at + 8..usize::min(at + 8 + size, bytes.len())];
+        match id {
+            b"fmt " => format = Some(self::format(chunk)?),
+            b"data" => data = Some(chunk),
+            _ => {}
+        }
+        // chunks are padded to an even number of bytes
+        at += 8 + size + (size & 1);
+    }
+
+    let format = format.ok_or(Error::InvalidWav("missing fmt chunk"))?;
+    let data = data.ok_or(Error::InvalidWav("missing data chunk"))?;
+
+    let width = format.bits as usize / 8;
+    if width == 0 {
+        return Err(Error::InvalidWav("unsupported sample format"));
+    }
+    let frame = width * format.channels as usize;
+
+    let mut samples = Vec::with_capacity(data.len() / frame);
+    for bytes in data.chunks_exact(frame) {
+        let mut sum = 0.0;
+        for channel in bytes.chunks_exact(width) {
+            sum += sample(&format, channel)?;
+        }
+        samples.push(sum / format.channels as f32);
+    }
+
+    Ok((format.sample_rate, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&22050u32.to_le_bytes());
+        bytes.extend_from_slice(&(22050 * (bits / 8 * channels) as u32).to_le_bytes());
+        bytes.extend_from_slice(&(bits / 8 * channels).to_le_bytes());
+        bytes.extend_from_slice(&bits.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn decodes_16_bit_stereo() {
+        let data: Vec<u8> = [16384i16, 16384, -32768, 0]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let (rate, samples) = decode(&wav(2, 16, &data)).unwrap();
+        assert_eq!(rate, 22050);
+        assert_eq!(samples, vec![0.5, -0.5]);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode(b"definitely not a wave file").is_err());
+    }
+}